@@ -3,28 +3,28 @@ baz := quux
 
 edit : main.o kbd.o command.o display.o \
 	insert.o search.o files.o utils.o
-    cc -o edit main.o kbd.o command.o display.o \
+	cc -o edit main.o kbd.o command.o display.o \
 		insert.o search.o files.o utils.o
 
 main.o : main.c defs.h
-        cc -c main.c
+	cc -c main.c
 kbd.o : kbd.c defs.h command.h
-        cc -c kbd.c
+	cc -c kbd.c
 
 foobar = bazquux
 
 command.o : command.c defs.h command.h
-        cc -c command.c
+	cc -c command.c
 display.o : display.c defs.h buffer.h
-        cc -c display.c
+	cc -c display.c
 insert.o : insert.c defs.h buffer.h
-        cc -c insert.c
+	cc -c insert.c
 search.o : search.c defs.h buffer.h
-        cc -c search.c
+	cc -c search.c
 files.o : files.c defs.h buffer.h command.h
-        cc -c files.c
+	cc -c files.c
 utils.o : utils.c defs.h
-        cc -c utils.c
+	cc -c utils.c
 clean :
-        rm edit main.o kbd.o command.o display.o \
-           insert.o search.o files.o utils.o
\ No newline at end of file
+	rm edit main.o kbd.o command.o display.o \
+	   insert.o search.o files.o utils.o
\ No newline at end of file
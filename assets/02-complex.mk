@@ -121,73 +121,73 @@ AUX =   README COPYING ChangeLog Makefile.in  \
 all:    tar rmt tar.info
 
 tar:    $(OBJS)
-        $(CC) $(LDFLAGS) -o $@ $(OBJS) $(LIBS)
+	$(CC) $(LDFLAGS) -o $@ $(OBJS) $(LIBS)
 
 rmt:    rmt.c
-        $(CC) $(CFLAGS) $(LDFLAGS) -o $@ rmt.c
+	$(CC) $(CFLAGS) $(LDFLAGS) -o $@ rmt.c
 
 tar.info: tar.texinfo
-        makeinfo tar.texinfo
+	makeinfo tar.texinfo
 
 .PHONY: install
 install: all
-        $(INSTALL) tar $(bindir)/$(binprefix)tar
-        -test ! -f rmt || $(INSTALL) rmt /etc/rmt
-        $(INSTALLDATA) $(srcdir)/tar.info* $(infodir)
+	$(INSTALL) tar $(bindir)/$(binprefix)tar
+	-test ! -f rmt || $(INSTALL) rmt /etc/rmt
+	$(INSTALLDATA) $(srcdir)/tar.info* $(infodir)
 
 $(OBJS): tar.h port.h testpad.h
 regex.o buffer.o tar.o: regex.h
 # getdate.y has 8 shift/reduce conflicts.
 
 testpad.h: testpad
-        ./testpad
+	./testpad
 
 testpad: testpad.o
-        $(CC) -o $@ testpad.o
+	$(CC) -o $@ testpad.o
 
 TAGS:   $(SRCS)
-        etags $(SRCS)
+	etags $(SRCS)
 
 .PHONY: clean
 clean:
-        rm -f *.o tar rmt testpad testpad.h core
+	rm -f *.o tar rmt testpad testpad.h core
 
 .PHONY: distclean
 distclean: clean
-        rm -f TAGS Makefile config.status
+	rm -f TAGS Makefile config.status
 
 .PHONY: realclean
 realclean: distclean
-        rm -f tar.info*
+	rm -f tar.info*
 
 .PHONY: shar
 shar: $(SRCS) $(AUX)
-        shar $(SRCS) $(AUX) | compress \
-          > tar-`sed -e '/version_string/!d' \
-                     -e 's/[^0-9.]*\([0-9.]*\).*/\1/' \
-                     -e q
-                     version.c`.shar.Z
+	shar $(SRCS) $(AUX) | compress \
+	  > tar-`sed -e '/version_string/!d' \
+	             -e 's/[^0-9.]*\([0-9.]*\).*/\1/' \
+	             -e q
+	             version.c`.shar.Z
 
 .PHONY: dist
 dist: $(SRCS) $(AUX)
-        echo tar-`sed \
-             -e '/version_string/!d' \
-             -e 's/[^0-9.]*\([0-9.]*\).*/\1/' \
-             -e q
-             version.c` > .fname
-        -rm -rf `cat .fname`
-        mkdir `cat .fname`
-        ln $(SRCS) $(AUX) `cat .fname`
-        tar chZf `cat .fname`.tar.Z `cat .fname`
-        -rm -rf `cat .fname` .fname
+	echo tar-`sed \
+	     -e '/version_string/!d' \
+	     -e 's/[^0-9.]*\([0-9.]*\).*/\1/' \
+	     -e q
+	     version.c` > .fname
+	-rm -rf `cat .fname`
+	mkdir `cat .fname`
+	ln $(SRCS) $(AUX) `cat .fname`
+	tar chZf `cat .fname`.tar.Z `cat .fname`
+	-rm -rf `cat .fname` .fname
 
 tar.zoo: $(SRCS) $(AUX)
-        -rm -rf tmp.dir
-        -mkdir tmp.dir
-        -rm tar.zoo
-        for X in $(SRCS) $(AUX) ; do \
-            echo $$X ; \
-            sed 's/$$/^M/' $$X \
-            > tmp.dir/$$X ; done
-        cd tmp.dir ; zoo aM ../tar.zoo *
-        -rm -rf tmp.dir
\ No newline at end of file
+	-rm -rf tmp.dir
+	-mkdir tmp.dir
+	-rm tar.zoo
+	for X in $(SRCS) $(AUX) ; do \
+	    echo $$X ; \
+	    sed 's/$$/^M/' $$X \
+	    > tmp.dir/$$X ; done
+	cd tmp.dir ; zoo aM ../tar.zoo *
+	-rm -rf tmp.dir
\ No newline at end of file
@@ -0,0 +1,22 @@
+# Conditionals, adapted from the GNU Make Manual.
+libs_for_gcc = -lgnu
+normal_libs =
+
+ifeq ($(CC),gcc)
+  libs=$(libs_for_gcc)
+else
+  libs=$(normal_libs)
+endif
+
+ifdef DEBUG
+CFLAGS = -g
+else ifneq "$(OPTIMIZE)" ""
+CFLAGS = -O2
+else
+  ifndef CFLAGS
+  CFLAGS = -O
+  endif
+endif
+
+foo: $(objects)
+	$(CC) -o foo $(objects) $(libs)
@@ -0,0 +1,4 @@
+PROGRAM = hello
+OPTIONAL = also-missing.mk
+
+hello: hello.o
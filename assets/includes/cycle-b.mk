@@ -0,0 +1 @@
+include assets/includes/cycle-a.mk
@@ -0,0 +1,6 @@
+# common.mk is found through the include directories
+include common.mk
+-include missing.mk
+sinclude $(OPTIONAL)
+
+all: $(PROGRAM)
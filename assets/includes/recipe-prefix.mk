@@ -0,0 +1,15 @@
+ifndef NOPE
+.RECIPEPREFIX = >
+endif
+ifdef NOPE
+.RECIPEPREFIX = :
+endif
+
+all: hello
+>echo all
+
+P = +
+.RECIPEPREFIX = $(P)
+
+hello:
++echo hello
@@ -0,0 +1,604 @@
+// The concrete syntax tree keeps every byte of a Makefile, including the
+// whitespace, comments and line continuations that the parser throws away,
+// so a tree can be edited and written back out without disturbing the rest
+// of the file. Writing out an unchanged tree gives back the original text.
+use std::borrow::Cow;
+use std::fmt;
+
+use crate::parser::{
+    find_comment, find_inline_recipe, parse_conditional_keyword, parse_statement, skip_statement,
+    State,
+};
+use crate::types::{Diagnostic, Span, Statement};
+
+// NodeKind is the construct a Node holds
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum NodeKind {
+    Makefile,
+    BlankLine,
+    CommentLine,
+    // a conditional holds its directive lines and the statements between them
+    Conditional,
+    Directive,
+    Variable,
+    Define,
+    TargetVariable,
+    Rule,
+    RecipeLine,
+    Include,
+    Export,
+    Undefine,
+    Vpath,
+    // a line that couldn't be parsed, kept as it was written
+    Error,
+}
+
+// TokenKind is what the text of a Token is
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum TokenKind {
+    Whitespace,
+    Newline,
+    // a backslash and the line ending after it
+    Continuation,
+    Comment,
+    Word,
+    Operator,
+    RecipePrefix,
+    // the rest of a line that isn't split up any further, such as a recipe
+    // command or a line of a define block
+    Text,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct Token<'a> {
+    pub kind: TokenKind,
+    pub text: Cow<'a, str>,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum Element<'a> {
+    Node(Node<'a>),
+    Token(Token<'a>),
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct Node<'a> {
+    pub kind: NodeKind,
+    pub span: Span<'a>,
+    pub children: Vec<Element<'a>>,
+}
+
+impl<'a> Node<'a> {
+    fn new(kind: NodeKind, span: Span<'a>) -> Self {
+        Node {
+            kind,
+            span,
+            children: Vec::new(),
+        }
+    }
+
+    // tokens returns every token under the node, in the order they were written
+    pub fn tokens(&self) -> Vec<&Token<'a>> {
+        let mut res = Vec::new();
+        for c in &self.children {
+            match c {
+                Element::Node(n) => res.extend(n.tokens()),
+                Element::Token(t) => res.push(t),
+            }
+        }
+        res
+    }
+
+    // nodes returns the nodes directly under this one
+    pub fn nodes(&self) -> Vec<&Node<'a>> {
+        self.children
+            .iter()
+            .filter_map(|c| match c {
+                Element::Node(n) => Some(n),
+                Element::Token(_) => None,
+            })
+            .collect()
+    }
+
+    fn push(&mut self, kind: TokenKind, text: &'a str) {
+        if !text.is_empty() {
+            self.children.push(Element::Token(Token {
+                kind,
+                text: Cow::Borrowed(text),
+            }));
+        }
+    }
+}
+
+impl fmt::Display for Node<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for t in self.tokens() {
+            f.write_str(&t.text)?;
+        }
+        Ok(())
+    }
+}
+
+// parse builds the concrete syntax tree of the makefile read from file. Lines
+// that can't be parsed become Error nodes and are reported in the
+// diagnostics, so the tree always holds the whole of the text.
+pub fn parse<'a>(file: &'a str, source: &'a str) -> (Node<'a>, Vec<Diagnostic<'a>>) {
+    let mut state = State::new(file, source);
+    let mut diagnostics = Vec::new();
+    // the makefile and the conditionals that are open inside it
+    let mut stack = vec![Node::new(NodeKind::Makefile, state.span(source))];
+    let mut i = source;
+    while !i.is_empty() {
+        let line = &i[..line_end(i)];
+        let rest = &i[line.len()..];
+        // in a recipe, anything after the prefix is passed to the shell
+        let recipe = state.recipe && line.starts_with(state.prefix);
+        if line.trim().is_empty() && !recipe {
+            add(&mut stack, lex_line(NodeKind::BlankLine, &state, line));
+            i = rest;
+            continue;
+        }
+        if line.trim_start().starts_with('#') && !recipe {
+            add(&mut stack, lex_line(NodeKind::CommentLine, &state, line));
+            i = rest;
+            continue;
+        }
+        if let (Ok((condition, keyword)), false) = (parse_conditional_keyword(line), recipe) {
+            state.conditional_line(keyword, condition);
+            let mut node = lex_line(NodeKind::Directive, &state, line);
+            match keyword {
+                "else" | "endif" if stack.len() == 1 => {
+                    diagnostics.push(Diagnostic {
+                        span: node.span,
+                        message: "else or endif without a matching conditional".to_string(),
+                    });
+                    node.kind = NodeKind::Error;
+                    add(&mut stack, node);
+                }
+                "else" => add(&mut stack, node),
+                "endif" => {
+                    add(&mut stack, node);
+                    let conditional = stack.pop().unwrap();
+                    add(&mut stack, conditional);
+                }
+                _ => {
+                    let mut conditional = Node::new(NodeKind::Conditional, node.span);
+                    conditional.children.push(Element::Node(node));
+                    stack.push(conditional);
+                }
+            }
+            i = rest;
+            continue;
+        }
+        match parse_statement(i, &mut state) {
+            Ok((j, statement)) => {
+                let raw = &i[..i.len() - j.len()];
+                let raw = &raw[..statement_end(&statement, raw, state.prefix)];
+                match statement {
+                    // recipe lines carried on past a conditional go in the conditional
+                    Statement::Recipe(_) => recipe_lines(
+                        stack.last_mut().unwrap(),
+                        &state,
+                        &physical_lines(raw),
+                        false,
+                    ),
+                    _ => add(&mut stack, statement_node(&statement, &state, raw)),
+                }
+                // anything left over is parsed again as blank or comment lines
+                i = &i[raw.len()..];
+            }
+            Err(e) => {
+                diagnostics.push(state.diagnostic(i, e));
+                let j = skip_statement(i, state.prefix);
+                let mut node = Node::new(NodeKind::Error, state.span(i));
+                lex(&mut node, &i[..i.len() - j.len()], false);
+                add(&mut stack, node);
+                i = j;
+            }
+        }
+    }
+    // conditionals without an endif end with the file
+    while stack.len() > 1 {
+        let conditional = stack.pop().unwrap();
+        diagnostics.push(Diagnostic {
+            span: conditional.span,
+            message: "missing endif".to_string(),
+        });
+        add(&mut stack, conditional);
+    }
+    (stack.pop().unwrap(), diagnostics)
+}
+
+fn add<'a>(stack: &mut [Node<'a>], node: Node<'a>) {
+    stack.last_mut().unwrap().children.push(Element::Node(node));
+}
+
+// line_end returns the length of the first line of i, including its line ending
+fn line_end(i: &str) -> usize {
+    i.find('\n').map_or(i.len(), |idx| idx + 1)
+}
+
+// physical_lines splits i into lines, each keeping its line ending
+fn physical_lines(i: &str) -> Vec<&str> {
+    let mut res = Vec::new();
+    let mut i = i;
+    while !i.is_empty() {
+        let end = line_end(i);
+        res.push(&i[..end]);
+        i = &i[end..];
+    }
+    res
+}
+
+// content returns line without its line ending
+fn content(line: &str) -> &str {
+    line.trim_end_matches('\n').trim_end_matches('\r')
+}
+
+// statement_end returns how much of raw belongs to the statement. The parser
+// takes the blank lines after a statement, and the comments after a recipe,
+// but they are kept as lines of their own in the tree.
+fn statement_end(statement: &Statement, raw: &str, prefix: char) -> usize {
+    let lines = physical_lines(raw);
+    let trailing = lines
+        .iter()
+        .skip(1)
+        .rev()
+        .take_while(|l| {
+            let l = content(l);
+            let comment = matches!(statement, Statement::Rule(_) | Statement::Recipe(_))
+                && !l.starts_with(prefix)
+                && l.trim_start().starts_with('#');
+            l.trim().is_empty() || comment
+        })
+        .map(|l| l.len())
+        .sum::<usize>();
+    raw.len() - trailing
+}
+
+fn statement_node<'a>(statement: &Statement<'a>, state: &State<'a>, raw: &'a str) -> Node<'a> {
+    let kind = match statement {
+        Statement::Variable(v) if v.define => NodeKind::Define,
+        Statement::TargetVariable(v) if v.variable.define => NodeKind::Define,
+        Statement::Variable(_) => NodeKind::Variable,
+        Statement::TargetVariable(_) => NodeKind::TargetVariable,
+        Statement::Rule(_) => NodeKind::Rule,
+        Statement::Include(_) => NodeKind::Include,
+        Statement::Export(_) => NodeKind::Export,
+        Statement::Undefine(_) => NodeKind::Undefine,
+        Statement::Vpath(_) => NodeKind::Vpath,
+        // conditionals are built a line at a time by parse
+        Statement::Conditional(_) => NodeKind::Conditional,
+        Statement::Recipe(_) => NodeKind::RecipeLine,
+    };
+    let mut node = Node::new(kind, state.span(raw));
+    match kind {
+        NodeKind::Rule => rule(&mut node, state, raw),
+        NodeKind::Define => {
+            // the body of a define is kept as it is, between the define and endef lines
+            let lines = physical_lines(raw);
+            let last = lines.len() - 1;
+            for (n, l) in lines.into_iter().enumerate() {
+                if n == 0 || n == last {
+                    lex(&mut node, l, true);
+                } else {
+                    node.push(TokenKind::Text, content(l));
+                    node.push(TokenKind::Newline, &l[content(l).len()..]);
+                }
+            }
+        }
+        NodeKind::Variable | NodeKind::TargetVariable => lex(&mut node, raw, true),
+        _ => lex(&mut node, raw, false),
+    }
+    node
+}
+
+// rule adds the rule line, which may be continued onto several lines, and
+// then a node for each line of the recipe
+fn rule<'a>(node: &mut Node<'a>, state: &State<'a>, raw: &'a str) {
+    let lines = physical_lines(raw);
+    let mut used = 0;
+    let mut continued = false;
+    for l in &lines {
+        used += 1;
+        let code = content(l);
+        let code = find_comment(code).map_or(code, |idx| &code[..idx]);
+        if let Some(idx) = find_inline_recipe(code) {
+            continued = content(l)[idx..].ends_with('\\');
+            break;
+        }
+        if code.len() < content(l).len() || !code.ends_with('\\') {
+            break;
+        }
+    }
+    let header: usize = lines[..used].iter().map(|l| l.len()).sum();
+    lex(node, &raw[..header], true);
+    recipe_lines(node, state, &lines[used..], continued);
+}
+
+// recipe_lines adds a node for each of lines, which are recipe lines along with
+// the blank lines and comments between them. continued is set when the first
+// line continues a command.
+fn recipe_lines<'a>(node: &mut Node<'a>, state: &State<'a>, lines: &[&'a str], continued: bool) {
+    let mut continued = continued;
+    for l in lines {
+        let text = content(l);
+        let kind = if continued || text.starts_with(state.prefix) {
+            continued = text.ends_with('\\');
+            NodeKind::RecipeLine
+        } else if text.trim().is_empty() {
+            NodeKind::BlankLine
+        } else {
+            NodeKind::CommentLine
+        };
+        if kind != NodeKind::RecipeLine {
+            node.children.push(Element::Node(lex_line(kind, state, l)));
+            continue;
+        }
+        let mut recipe = Node::new(kind, state.span(l));
+        let command = match text.strip_prefix(state.prefix) {
+            Some(command) => {
+                recipe.push(TokenKind::RecipePrefix, &text[..text.len() - command.len()]);
+                command
+            }
+            None => text,
+        };
+        recipe.push(TokenKind::Text, command);
+        recipe.push(TokenKind::Newline, &l[text.len()..]);
+        node.children.push(Element::Node(recipe));
+    }
+}
+
+fn lex_line<'a>(kind: NodeKind, state: &State<'a>, line: &'a str) -> Node<'a> {
+    let mut node = Node::new(kind, state.span(line));
+    lex(&mut node, line, false);
+    node
+}
+
+// Operators are only split from the words around them in variable
+// definitions and rules. The longest operator that matches is used.
+const OPERATORS: [&str; 12] = [
+    "::=", ":=", "+=", "?=", "!=", "&::", "&:", "::", "=", ":", "|", ";",
+];
+
+// lex adds the tokens of text to node. Once an assignment operator has been
+// seen the rest of the line is the value, so operators are no longer split
+// out, and the rest of the line after a ; is an inline recipe.
+fn lex<'a>(node: &mut Node<'a>, text: &'a str, operators: bool) {
+    let mut operators = operators;
+    let mut i = text;
+    while !i.is_empty() {
+        let operator = OPERATORS.iter().find(|op| operators && i.starts_with(**op));
+        let (kind, len) = if i.starts_with('\n') {
+            (TokenKind::Newline, 1)
+        } else if i.starts_with("\r\n") {
+            (TokenKind::Newline, 2)
+        } else if i.starts_with("\\\n") {
+            (TokenKind::Continuation, 2)
+        } else if i.starts_with("\\\r\n") {
+            (TokenKind::Continuation, 3)
+        } else if i.starts_with([' ', '\t']) {
+            let len = i.len() - i.trim_start_matches([' ', '\t']).len();
+            (TokenKind::Whitespace, len)
+        } else if i.starts_with('#') {
+            (TokenKind::Comment, content(&i[..line_end(i)]).len())
+        } else if let Some(&";") = operator {
+            node.push(TokenKind::Operator, ";");
+            i = &i[1..];
+            let len = i.len() - i.trim_start_matches([' ', '\t']).len();
+            node.push(TokenKind::Whitespace, &i[..len]);
+            i = &i[len..];
+            (TokenKind::Text, content(&i[..line_end(i)]).len())
+        } else if let Some(op) = operator {
+            if op.ends_with('=') {
+                operators = false;
+            }
+            (TokenKind::Operator, op.len())
+        } else {
+            (TokenKind::Word, word_len(i, operators))
+        };
+        node.push(kind, &i[..len]);
+        i = &i[len..];
+        if kind == TokenKind::Newline {
+            operators = true;
+        }
+    }
+}
+
+// word_len returns the length of the word at the start of i. Spaces inside
+// a variable reference or function call are part of the word, as is a #
+// escaped with a backslash.
+fn word_len(i: &str, operators: bool) -> usize {
+    let mut depth = 0;
+    let mut chars = i.char_indices().peekable();
+    while let Some((idx, c)) = chars.next() {
+        let rest = &i[idx..];
+        let end = match c {
+            '\n' | '\r' => true,
+            '\\' if rest[1..].starts_with(['\n', '\r']) => true,
+            '\\' if rest[1..].starts_with('#') => {
+                chars.next();
+                false
+            }
+            '#' => depth == 0,
+            ' ' | '\t' => depth == 0,
+            '$' if rest[1..].starts_with(['(', '{']) => {
+                chars.next();
+                depth += 1;
+                false
+            }
+            '(' | '{' if depth > 0 => {
+                depth += 1;
+                false
+            }
+            ')' | '}' if depth > 0 => {
+                depth -= 1;
+                false
+            }
+            _ => depth == 0 && operators && OPERATORS.iter().any(|op| rest.starts_with(op)),
+        };
+        if end {
+            // a word is never empty, so lexing always moves on
+            return idx.max(c.len_utf8());
+        }
+    }
+    i.len()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::fs;
+
+    fn kinds(node: &Node) -> Vec<NodeKind> {
+        node.nodes().iter().map(|n| n.kind).collect()
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let data = "# header\r\nCC = gcc # compiler\r\n\r\nifdef CC\n  SRCS := a.c \\\n     b.c\nelse\nendif\nall: a.o b.o | obj ; @echo \\\n\tdone\n\t$(CC) -o $@ $^\n\n# after\n\t-rm -f *.o\nnot a rule\n\tskipped\ndefine BODY\n  text # kept\nendef\nfoo: CFLAGS += -g\nendif";
+        let (tree, diagnostics) = parse("Makefile", data);
+        assert_eq!(tree.to_string(), data);
+        let messages: Vec<String> = diagnostics.iter().map(|d| d.to_string()).collect();
+        assert_eq!(
+            messages,
+            vec![
+                "Makefile:15:1: missing separator",
+                "Makefile:21:1: else or endif without a matching conditional",
+            ]
+        );
+        use NodeKind::*;
+        assert_eq!(
+            kinds(&tree),
+            vec![
+                CommentLine,
+                Variable,
+                BlankLine,
+                Conditional,
+                Rule,
+                Error,
+                Define,
+                TargetVariable,
+                Error,
+            ]
+        );
+        let nodes = tree.nodes();
+        assert_eq!(
+            kinds(nodes[3]),
+            vec![Directive, Variable, Directive, Directive]
+        );
+        assert_eq!(nodes[3].nodes()[1].span.to_string(), "Makefile:5:3");
+        // the recipe line after the comment belongs to the rule
+        assert_eq!(
+            kinds(nodes[4]),
+            vec![RecipeLine, RecipeLine, BlankLine, CommentLine, RecipeLine]
+        );
+
+        let tokens: Vec<(TokenKind, &str)> = nodes[1]
+            .tokens()
+            .iter()
+            .map(|t| (t.kind, t.text.as_ref()))
+            .collect();
+        assert_eq!(
+            tokens,
+            vec![
+                (TokenKind::Word, "CC"),
+                (TokenKind::Whitespace, " "),
+                (TokenKind::Operator, "="),
+                (TokenKind::Whitespace, " "),
+                (TokenKind::Word, "gcc"),
+                (TokenKind::Whitespace, " "),
+                (TokenKind::Comment, "# compiler"),
+                (TokenKind::Newline, "\r\n"),
+            ]
+        );
+        let tokens: Vec<(TokenKind, &str)> = nodes[4]
+            .tokens()
+            .iter()
+            .map(|t| (t.kind, t.text.as_ref()))
+            .filter(|(k, _)| *k != TokenKind::Whitespace)
+            .take(10)
+            .collect();
+        assert_eq!(
+            tokens,
+            vec![
+                (TokenKind::Word, "all"),
+                (TokenKind::Operator, ":"),
+                (TokenKind::Word, "a.o"),
+                (TokenKind::Word, "b.o"),
+                (TokenKind::Operator, "|"),
+                (TokenKind::Word, "obj"),
+                (TokenKind::Operator, ";"),
+                (TokenKind::Text, "@echo \\"),
+                (TokenKind::Newline, "\n"),
+                (TokenKind::RecipePrefix, "\t"),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_inline_recipe_in_reference() {
+        // the ; belongs to the reference, so the rule line carries on
+        let data = "all: $(shell a;b) \\\n  c\n\techo\n";
+        let (tree, diagnostics) = parse("Makefile", data);
+        assert!(diagnostics.is_empty());
+        assert_eq!(tree.to_string(), data);
+        let rule = tree.nodes()[0];
+        assert_eq!(kinds(rule), vec![NodeKind::RecipeLine]);
+        let words: Vec<&str> = rule
+            .tokens()
+            .iter()
+            .filter(|t| t.kind == TokenKind::Word || t.kind == TokenKind::Text)
+            .map(|t| t.text.as_ref())
+            .collect();
+        assert_eq!(words, vec!["all", "$(shell a;b)", "c", "echo"]);
+    }
+
+    #[test]
+    fn test_recipe_in_conditional() {
+        let data =
+            "X = 1\nall:\nifdef X\n\techo a\nelse\n\techo b\nendif\nb:\n\tifeq ($(X),1)\n\tendif\n";
+        let (tree, diagnostics) = parse("Makefile", data);
+        assert!(diagnostics.is_empty());
+        assert_eq!(tree.to_string(), data);
+        use NodeKind::*;
+        assert_eq!(kinds(&tree), vec![Variable, Rule, Conditional, Rule]);
+        let nodes = tree.nodes();
+        assert_eq!(
+            kinds(nodes[2]),
+            vec![Directive, RecipeLine, Directive, RecipeLine, Directive]
+        );
+        assert_eq!(nodes[2].nodes()[3].span.to_string(), "Makefile:6:2");
+        assert_eq!(kinds(nodes[3]), vec![RecipeLine, RecipeLine]);
+    }
+
+    #[test]
+    fn test_round_trip_assets() {
+        for name in &["01-simple.mk", "02-complex.mk", "03-conditionals.mk"] {
+            let data = fs::read_to_string(format!("assets/{}", name)).unwrap();
+            let (tree, diagnostics) = parse(name, &data);
+            assert!(diagnostics.is_empty(), "{}", name);
+            assert_eq!(tree.to_string(), data, "{}", name);
+        }
+    }
+
+    #[test]
+    fn test_edit() {
+        let data = "CC = gcc\nall: main.o\n\t$(CC) -o $@ $^\n";
+        let (mut tree, _) = parse("", data);
+        // replace the value of CC and leave everything else alone
+        if let Element::Node(n) = &mut tree.children[0] {
+            for c in n.children.iter_mut() {
+                if let Element::Token(t) = c {
+                    if t.text == "gcc" {
+                        t.text = Cow::Owned("clang".to_string());
+                    }
+                }
+            }
+        }
+        assert_eq!(
+            tree.to_string(),
+            "CC = clang\nall: main.o\n\t$(CC) -o $@ $^\n"
+        );
+    }
+}
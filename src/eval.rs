@@ -0,0 +1,1213 @@
+use std::cmp::Reverse;
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use typed_arena::Arena;
+
+use crate::functions;
+use crate::parser::{
+    parse_expression, parse_makefile, parse_with_prefixes, recipe_prefix, rule_kind,
+    unescape_hashes,
+};
+use crate::pattern;
+use crate::types::*;
+use crate::vpath::{self, Search};
+
+// Origin records where a variable was defined. Definitions in the Makefile
+// don't replace variables from the command line unless they use override.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Origin {
+    Automatic,
+    Environment,
+    File,
+    CommandLine,
+    Override,
+}
+
+// Value is the text of a variable and whether it is expanded each time it is used.
+// A private value remembers the one it replaced so that it can be restored
+// for prerequisites, which don't inherit it.
+#[derive(Debug, Clone)]
+struct Value {
+    text: String,
+    recursive: bool,
+    origin: Origin,
+    private: bool,
+    shadowed: Option<Box<Value>>,
+}
+
+// TargetValue is a target-specific or pattern-specific variable, kept until
+// the scope of a matching target is needed. Simple variables are expanded
+// when they are read, so text holds the expanded value.
+#[derive(Debug, Clone)]
+struct TargetValue {
+    targets: Vec<String>,
+    name: String,
+    assignment: Assignment,
+    text: String,
+    modifiers: Modifiers,
+}
+
+// Evaluator reads the statements of a Makefile in order, keeping track of the
+// variables defined so far so that conditionals can be resolved. Included
+// files are read into `sources` so that their statements can be spliced into
+// the Makefile being evaluated.
+#[derive(Clone)]
+pub struct Evaluator<'a> {
+    variables: HashMap<String, Value>,
+    // target-specific and pattern-specific variables in the order they were read
+    targets: Vec<TargetValue>,
+    // variables explicitly exported or unexported, by name
+    exports: HashMap<String, bool>,
+    export_all: bool,
+    // directories given by vpath directives
+    vpath: Search,
+    sources: &'a Arena<String>,
+    include_dirs: Vec<PathBuf>,
+    // files currently being read, used to detect include cycles
+    files: Vec<PathBuf>,
+    // the recipe prefix the current file was parsed with, the number of
+    // conditionals being evaluated in it and the prefixes the parser was
+    // given for it, by line. mismatch is set when an assignment to
+    // .RECIPEPREFIX sets a prefix the parser didn't know about.
+    prefix: char,
+    conditionals: usize,
+    prefixes: HashMap<usize, char>,
+    mismatch: Option<(usize, char)>,
+}
+
+// join turns a value split over several lines with backslash-newline into a
+// single line, with the escaping removed from each \#
+fn join(parts: &[&str]) -> String {
+    let value = parts
+        .iter()
+        .map(|p| p.strip_suffix('\\').unwrap_or(p).trim())
+        .filter(|p| !p.is_empty())
+        .collect::<Vec<_>>()
+        .join(" ");
+    unescape_hashes(&value)
+}
+
+// without_empty_rules drops the rules whose targets expanded to nothing
+fn without_empty_rules(mut statements: Vec<Statement>) -> Vec<Statement> {
+    statements.retain(|s| !matches!(s, Statement::Rule(r) if r.targets.is_empty()));
+    statements
+}
+
+// at prefixes an error with where it happened
+fn at(span: Span, e: String) -> String {
+    format!("{}: {}", span, e)
+}
+
+impl<'a> Evaluator<'a> {
+    pub fn new(sources: &'a Arena<String>) -> Evaluator<'a> {
+        Evaluator {
+            variables: HashMap::new(),
+            targets: Vec::new(),
+            exports: HashMap::new(),
+            export_all: false,
+            vpath: Search::default(),
+            sources,
+            include_dirs: Vec::new(),
+            files: Vec::new(),
+            prefix: '\t',
+            conditionals: 0,
+            prefixes: HashMap::new(),
+            mismatch: None,
+        }
+    }
+
+    // include_dirs sets the directories searched for included files that
+    // aren't found relative to the current directory
+    pub fn include_dirs(&mut self, dirs: Vec<PathBuf>) {
+        self.include_dirs = dirs;
+    }
+
+    // define_environment adds variables from the environment. These are
+    // exported to recipes unless the Makefile says otherwise.
+    pub fn define_environment<I: IntoIterator<Item = (String, String)>>(&mut self, vars: I) {
+        for (name, text) in vars {
+            self.variables.insert(
+                name,
+                Value {
+                    text,
+                    recursive: true,
+                    origin: Origin::Environment,
+                    private: false,
+                    shadowed: None,
+                },
+            );
+        }
+    }
+
+    // define_automatic sets automatic variables such as $@ for the target
+    // whose recipe is being expanded
+    pub fn define_automatic<I: IntoIterator<Item = (String, String)>>(&mut self, vars: I) {
+        for (name, text) in vars {
+            self.variables.insert(
+                name,
+                Value {
+                    text,
+                    recursive: false,
+                    origin: Origin::Automatic,
+                    private: false,
+                    shadowed: None,
+                },
+            );
+        }
+    }
+
+    // define_command_line adds a variable given on the command line as NAME=value
+    pub fn define_command_line(&mut self, definition: &str) -> Result<(), String> {
+        match parse_makefile(definition) {
+            Ok((_, m)) => match m.statements.as_slice() {
+                [Statement::Variable(v)] => self.define(v, Origin::CommandLine),
+                _ => Err(format!("{}: not a variable definition", definition)),
+            },
+            Err(_) => Err(format!("{}: not a variable definition", definition)),
+        }
+    }
+
+    // evaluate returns the Makefile with every conditional replaced by the
+    // statements of the branch that was taken and every include replaced by
+    // the statements of the included files
+    pub fn evaluate(&mut self, makefile: Makefile<'a>) -> Result<Makefile<'a>, String> {
+        let mut statements = Vec::new();
+        self.evaluate_statements(makefile.statements, &mut statements)?;
+        Ok(Makefile {
+            statements: without_empty_rules(statements),
+        })
+    }
+
+    // evaluate_file reads, parses and evaluates the Makefile at path
+    pub fn evaluate_file(&mut self, path: &Path) -> Result<Makefile<'a>, String> {
+        let mut statements = Vec::new();
+        self.read(path, &mut statements)?;
+        Ok(Makefile {
+            statements: without_empty_rules(statements),
+        })
+    }
+
+    fn evaluate_statements(
+        &mut self,
+        statements: Vec<Statement<'a>>,
+        out: &mut Vec<Statement<'a>>,
+    ) -> Result<(), String> {
+        for s in statements {
+            match s {
+                Statement::Variable(v) => {
+                    self.define(&v, Origin::File).map_err(|e| at(v.span, e))?;
+                    if v.name == ".RECIPEPREFIX" {
+                        self.recipe_prefix(&v).map_err(|e| at(v.span, e))?;
+                    }
+                    out.push(Statement::Variable(v));
+                }
+                Statement::Rule(r) => {
+                    let span = r.span;
+                    // a rule whose targets expand to nothing is ignored, but
+                    // is kept until the end to take any recipe that follows
+                    let r = self.expand_rule(r).map_err(|e| at(span, e))?;
+                    out.push(Statement::Rule(r));
+                }
+                Statement::Recipe(r) => match out.last_mut() {
+                    Some(Statement::Rule(rule)) => rule.recipe.extend(r.lines),
+                    _ => {
+                        return Err(at(
+                            r.span,
+                            "recipe commences before first target".to_string(),
+                        ))
+                    }
+                },
+                Statement::TargetVariable(v) => {
+                    self.define_target(&v).map_err(|e| at(v.span, e))?;
+                    out.push(Statement::TargetVariable(v));
+                }
+                Statement::Conditional(c) => {
+                    let branch = if self.test(&c.condition).map_err(|e| at(c.span, e))? {
+                        c.then
+                    } else {
+                        c.otherwise
+                    };
+                    self.conditionals += 1;
+                    let res = self.evaluate_statements(branch, out);
+                    self.conditionals -= 1;
+                    res?;
+                }
+                Statement::Include(i) => self.include(&i, out)?,
+                Statement::Undefine(u) => {
+                    if u.r#override || !self.is_protected(u.name) {
+                        self.variables.remove(u.name);
+                    }
+                    out.push(Statement::Undefine(u));
+                }
+                Statement::Export(e) => {
+                    self.export(&e).map_err(|err| at(e.span, err))?;
+                    out.push(Statement::Export(e));
+                }
+                Statement::Vpath(v) => {
+                    let pattern = match v.pattern {
+                        Some(p) => Some(self.expand(p).map_err(|e| at(v.span, e))?),
+                        None => None,
+                    };
+                    let mut dirs = Vec::new();
+                    for d in &v.directories {
+                        let d = self.expand(d).map_err(|e| at(v.span, e))?;
+                        dirs.extend(vpath::split_dirs(&d));
+                    }
+                    match pattern {
+                        Some(pattern) if !dirs.is_empty() => self.vpath.add(&pattern, dirs),
+                        pattern => self.vpath.clear(pattern.as_deref()),
+                    }
+                    out.push(Statement::Vpath(v));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn include(
+        &mut self,
+        include: &Include<'a>,
+        out: &mut Vec<Statement<'a>>,
+    ) -> Result<(), String> {
+        let mut names = Vec::new();
+        for n in &include.names {
+            let n = self.expand(n).map_err(|e| at(include.span, e))?;
+            names.extend(n.split_whitespace().map(String::from));
+        }
+        for name in names {
+            match self.find(&name) {
+                Some(path) => self.read(&path, out)?,
+                None if include.optional => {}
+                None => {
+                    return Err(format!(
+                        "{}: {}: No such file or directory",
+                        include.span, name
+                    ))
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // find looks for an included file relative to the current directory and
+    // then in each of the include directories
+    fn find(&self, name: &str) -> Option<PathBuf> {
+        let path = Path::new(name);
+        if path.is_file() {
+            return Some(path.to_path_buf());
+        }
+        if path.is_absolute() {
+            return None;
+        }
+        self.include_dirs
+            .iter()
+            .map(|d| d.join(path))
+            .find(|p| p.is_file())
+    }
+
+    // read parses the file at path and evaluates its statements into out
+    fn read(&mut self, path: &Path, out: &mut Vec<Statement<'a>>) -> Result<(), String> {
+        let canonical = fs::canonicalize(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+        if self.files.contains(&canonical) {
+            let mut cycle: Vec<String> =
+                self.files.iter().map(|f| f.display().to_string()).collect();
+            cycle.push(canonical.display().to_string());
+            return Err(format!("include cycle: {}", cycle.join(" -> ")));
+        }
+        let contents =
+            fs::read_to_string(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+        let contents: &'a str = self.sources.alloc(contents);
+        // the name is kept alongside the contents for the spans that refer to it
+        let file: &'a str = self.sources.alloc(path.display().to_string());
+        // the prefix set by a .RECIPEPREFIX the parser couldn't follow is
+        // found when it is evaluated, and the file is read again with it
+        let mut prefixes = HashMap::new();
+        loop {
+            // every syntax error in the file is reported, not just the first
+            let (makefile, diagnostics) = parse_with_prefixes(file, contents, prefixes.clone());
+            let saved = self.clone();
+            let len = out.len();
+            self.files.push(canonical.clone());
+            self.prefix = '\t';
+            self.conditionals = 0;
+            self.prefixes = prefixes.clone();
+            let res = self.evaluate_statements(makefile.statements, out);
+            match self.mismatch.take() {
+                Some((line, prefix)) if !prefixes.contains_key(&line) => {
+                    *self = saved;
+                    out.truncate(len);
+                    prefixes.insert(line, prefix);
+                    continue;
+                }
+                _ if !diagnostics.is_empty() => {
+                    *self = saved;
+                    out.truncate(len);
+                    let messages: Vec<String> = diagnostics.iter().map(|d| d.to_string()).collect();
+                    return Err(messages.join("\n"));
+                }
+                _ => {}
+            }
+            self.files = saved.files;
+            self.prefix = saved.prefix;
+            self.conditionals = saved.conditionals;
+            self.prefixes = saved.prefixes;
+            return res;
+        }
+    }
+
+    // recipe_prefix checks that an assignment to .RECIPEPREFIX leaves the
+    // prefix the same as the one the parser used for the lines after it. The
+    // parser can't follow an assignment inside a conditional or with a value
+    // that needs expanding until it is told the prefix, so when they differ
+    // the line and prefix are kept in mismatch for the file to be read again.
+    fn recipe_prefix(&mut self, v: &Variable) -> Result<(), String> {
+        self.prefix =
+            recipe_prefix(v, self.conditionals > 0, &self.prefixes).unwrap_or(self.prefix);
+        let value = self.expand("$(.RECIPEPREFIX)")?;
+        let prefix = value.chars().next().unwrap_or('\t');
+        if prefix != self.prefix {
+            self.mismatch = Some((v.span.line, prefix));
+            return Err(
+                "setting .RECIPEPREFIX inside a conditional or from a reference is only supported when reading a file"
+                    .to_string(),
+            );
+        }
+        Ok(())
+    }
+
+    // is_protected is true when a variable was set on the command line or with
+    // override, so an ordinary definition in the Makefile must not replace it
+    fn is_protected(&self, name: &str) -> bool {
+        self.variables
+            .get(name)
+            .is_some_and(|v| v.origin == Origin::CommandLine || v.origin == Origin::Override)
+    }
+
+    fn define(&mut self, v: &Variable, origin: Origin) -> Result<(), String> {
+        let text = if v.define {
+            v.value.concat()
+        } else {
+            join(&v.value)
+        };
+        let text = match v.assignment {
+            Assignment::Simple => self.expand(&text)?,
+            _ => text,
+        };
+        self.assign(v.name, v.assignment, text, v.modifiers, origin)
+    }
+
+    // assign sets a variable from the text of its definition. The text of a
+    // simple variable has already been expanded.
+    fn assign(
+        &mut self,
+        name: &str,
+        assignment: Assignment,
+        text: String,
+        modifiers: Modifiers,
+        origin: Origin,
+    ) -> Result<(), String> {
+        if modifiers.export {
+            self.exports.insert(name.to_string(), true);
+        }
+        let origin = if modifiers.r#override {
+            Origin::Override
+        } else {
+            origin
+        };
+        if origin == Origin::File && self.is_protected(name) {
+            return Ok(());
+        }
+        let (text, recursive) = match assignment {
+            Assignment::Recursive => (text, true),
+            Assignment::Simple => (text, false),
+            Assignment::Conditional => match self.variables.get(name) {
+                Some(_) => return Ok(()),
+                None => (text, true),
+            },
+            Assignment::Append => match self.variables.get(name) {
+                Some(old) => {
+                    let text = if old.recursive {
+                        text
+                    } else {
+                        self.expand(&text)?
+                    };
+                    if old.text.is_empty() || text.is_empty() {
+                        (old.text.clone() + &text, old.recursive)
+                    } else {
+                        (format!("{} {}", old.text, text), old.recursive)
+                    }
+                }
+                None => (text, true),
+            },
+            // Running shell commands is not supported yet
+            Assignment::Shell => return Ok(()),
+        };
+        let shadowed = match self.variables.remove(name) {
+            Some(old) if modifiers.private && old.private => old.shadowed,
+            Some(old) if modifiers.private => Some(Box::new(old)),
+            _ => None,
+        };
+        self.variables.insert(
+            name.to_string(),
+            Value {
+                text,
+                recursive,
+                origin,
+                private: modifiers.private,
+                shadowed,
+            },
+        );
+        Ok(())
+    }
+
+    // expand_rule expands the targets and prerequisites of a rule as soon as
+    // it is read. Its recipe is left until it is run.
+    fn expand_rule(&self, mut r: Rule<'a>) -> Result<Rule<'a>, String> {
+        let mut targets = Vec::new();
+        for t in &r.targets {
+            targets.extend(self.expand_words(t)?);
+        }
+        r.targets = targets;
+        r.kind = match r.kind {
+            RuleKind::StaticPattern(p) => {
+                let p = self.expand_words(p)?.first().copied().unwrap_or("");
+                if !p.contains('%') {
+                    return Err("target pattern contains no '%'".to_string());
+                }
+                RuleKind::StaticPattern(p)
+            }
+            _ => rule_kind(&r.targets)?,
+        };
+        r.prerequsities = self.expand_prerequisites(&r.prerequsities)?;
+        r.order_only = self.expand_prerequisites(&r.order_only)?;
+        Ok(r)
+    }
+
+    // expand_prerequisites expands each prerequisite into the words it refers
+    // to, which are all given its span
+    fn expand_prerequisites(
+        &self,
+        prerequisites: &[Prerequisite<'a>],
+    ) -> Result<Vec<Prerequisite<'a>>, String> {
+        let mut res = Vec::new();
+        for p in prerequisites {
+            for name in self.expand_words(p.name)? {
+                res.push(Prerequisite { name, span: p.span });
+            }
+        }
+        Ok(res)
+    }
+
+    // expand_words returns the words text expands to, with any escaped \#
+    // turned into #. The expanded text is kept in sources, so that it lives
+    // as long as the Makefile does.
+    fn expand_words(&self, text: &'a str) -> Result<Vec<&'a str>, String> {
+        if !text.contains(['$', '#']) {
+            return Ok(vec![text]);
+        }
+        let text: &'a str = self.sources.alloc(self.expand(&unescape_hashes(text))?);
+        Ok(text.split_whitespace().collect())
+    }
+
+    // define_target records a target-specific or pattern-specific variable
+    fn define_target(&mut self, v: &TargetVariable) -> Result<(), String> {
+        let mut targets = Vec::new();
+        for t in &v.targets {
+            targets.extend(self.expand(t)?.split_whitespace().map(String::from));
+        }
+        let variable = &v.variable;
+        let text = if variable.define {
+            variable.value.concat()
+        } else {
+            join(&variable.value)
+        };
+        let text = match variable.assignment {
+            Assignment::Simple => self.expand(&text)?,
+            _ => text,
+        };
+        self.targets.push(TargetValue {
+            targets,
+            name: variable.name.to_string(),
+            assignment: variable.assignment,
+            text,
+            modifiers: variable.modifiers,
+        });
+        Ok(())
+    }
+
+    // search returns the directories to search for prerequisites, from the
+    // vpath directives and the current value of VPATH
+    pub fn search(&self) -> Result<Search, String> {
+        let mut res = self.vpath.clone();
+        res.general(vpath::split_dirs(&self.expand("$(VPATH)")?));
+        Ok(res)
+    }
+
+    // scope returns an Evaluator for making target as a prerequisite of the
+    // target this one was made for, or as a goal when called on the
+    // Evaluator for the Makefile. Private variables aren't inherited, then
+    // the pattern-specific variables for target are applied, those with
+    // shorter stems last, followed by its target-specific variables.
+    pub fn scope(&self, target: &str) -> Result<Evaluator<'a>, String> {
+        let mut res = self.clone();
+        res.variables = HashMap::new();
+        for (name, value) in self.variables.clone() {
+            let value = match value.shadowed {
+                Some(shadowed) if value.private => *shadowed,
+                None if value.private => continue,
+                _ => value,
+            };
+            res.variables.insert(name, value);
+        }
+
+        let mut patterns: Vec<(usize, &TargetValue)> = self
+            .targets
+            .iter()
+            .filter_map(|v| {
+                v.targets
+                    .iter()
+                    .filter(|t| t.contains('%'))
+                    .filter_map(|t| pattern::match_target(t, target))
+                    .map(|m| m.full_stem().len())
+                    .min()
+                    .map(|len| (len, v))
+            })
+            .collect();
+        patterns.sort_by_key(|(len, _)| Reverse(*len));
+        let explicit = self
+            .targets
+            .iter()
+            .filter(|v| v.targets.iter().any(|t| t == target));
+        for v in patterns.into_iter().map(|(_, v)| v).chain(explicit) {
+            res.assign(
+                &v.name,
+                v.assignment,
+                v.text.clone(),
+                v.modifiers,
+                Origin::File,
+            )?;
+        }
+        Ok(res)
+    }
+
+    fn export(&mut self, e: &Export) -> Result<(), String> {
+        if e.names.is_empty() {
+            self.export_all = e.export;
+            return Ok(());
+        }
+        for n in &e.names {
+            for name in self.expand(n)?.split_whitespace() {
+                self.exports.insert(name.to_string(), e.export);
+            }
+        }
+        Ok(())
+    }
+
+    // environment returns the variables to pass to the environment of recipes.
+    // Variables are exported if they were marked with export, or if they came
+    // from the environment or export was used on its own, unless they were
+    // marked with unexport. Private variables are never seen by recipes.
+    pub fn environment(&self) -> Result<Vec<(String, String)>, String> {
+        let mut res = self
+            .variables
+            .iter()
+            .filter(|(_, v)| !v.private)
+            .filter(|(name, v)| match self.exports.get(*name) {
+                Some(export) => *export,
+                None => {
+                    v.origin == Origin::Environment
+                        || (self.export_all
+                            && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_'))
+                }
+            })
+            .map(|(name, _)| Ok((name.clone(), self.lookup(name, &mut Vec::new())?)))
+            .collect::<Result<Vec<_>, String>>()?;
+        res.sort();
+        Ok(res)
+    }
+
+    fn test(&self, condition: &Condition) -> Result<bool, String> {
+        Ok(match condition {
+            Condition::IfEq(a, b) => self.expand(a)? == self.expand(b)?,
+            Condition::IfNeq(a, b) => self.expand(a)? != self.expand(b)?,
+            Condition::IfDef(name) => self.is_defined(&self.expand(name)?),
+            Condition::IfNdef(name) => !self.is_defined(&self.expand(name)?),
+        })
+    }
+
+    // A variable only counts as defined if it has a non-empty value
+    fn is_defined(&self, name: &str) -> bool {
+        self.variables.get(name).is_some_and(|v| !v.text.is_empty())
+    }
+
+    // expand replaces the variable references in text with their values
+    pub fn expand(&self, text: &str) -> Result<String, String> {
+        self.expand_in(text, &mut Vec::new())
+    }
+
+    // expand_in keeps a stack of the recursive variables being expanded, so
+    // that one referring to itself is reported instead of recursing forever
+    fn expand_in(&self, text: &str, stack: &mut Vec<String>) -> Result<String, String> {
+        match parse_expression(text) {
+            Ok((_, exprs)) => self.expand_exprs(&exprs, stack),
+            Err(_) => Err("unterminated variable reference".to_string()),
+        }
+    }
+
+    fn expand_exprs(&self, exprs: &[Expr], stack: &mut Vec<String>) -> Result<String, String> {
+        let mut res = String::new();
+        for e in exprs {
+            match e {
+                Expr::Literal(text) => res.push_str(text),
+                Expr::Reference(name) => {
+                    let name = self.expand_exprs(name, stack)?;
+                    res.push_str(&self.lookup(&name, stack)?);
+                }
+                Expr::Substitution { name, from, to } => {
+                    let name = self.expand_exprs(name, stack)?;
+                    let text = self.lookup(&name, stack)?;
+                    let from = self.expand_exprs(from, stack)?;
+                    let to = self.expand_exprs(to, stack)?;
+                    res.push_str(&functions::substitution(&text, &from, &to));
+                }
+                Expr::Call { function, args } => {
+                    let args = args
+                        .iter()
+                        .map(|a| self.expand_exprs(a, stack))
+                        .collect::<Result<Vec<_>, _>>()?;
+                    res.push_str(&functions::call(function, &args)?);
+                }
+            }
+        }
+        Ok(res)
+    }
+
+    // lookup returns the value of a variable. A recursive variable is
+    // expanded every time it is used, which is when a reference to itself
+    // would never end.
+    fn lookup(&self, name: &str, stack: &mut Vec<String>) -> Result<String, String> {
+        match self.variables.get(name) {
+            Some(v) if v.recursive => {
+                if stack.iter().any(|n| n == name) {
+                    return Err(format!("recursive variable '{}' references itself", name));
+                }
+                stack.push(name.to_string());
+                let res = self.expand_in(&v.text, stack);
+                stack.pop();
+                res
+            }
+            Some(v) => Ok(v.text.clone()),
+            None => Ok(String::new()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::parser::parse_makefile;
+
+    fn targets<'a>(makefile: &Makefile<'a>) -> Vec<&'a str> {
+        makefile
+            .rules()
+            .iter()
+            .flat_map(|r| r.targets.clone())
+            .collect()
+    }
+
+    #[test]
+    fn test_expand() {
+        let (_, m) =
+            parse_makefile("a = 1\nb = $(a) ${a} $$a\nc := $(b)$(missing)\nx1 = nested\n").unwrap();
+        let sources = Arena::new();
+        let mut e = Evaluator::new(&sources);
+        e.evaluate(m).unwrap();
+        assert_eq!(e.expand("$(b)").unwrap(), "1 1 $a");
+        assert_eq!(e.expand("$(c)").unwrap(), "1 1 $a");
+        assert_eq!(e.expand("$(x$(a))").unwrap(), "nested");
+    }
+
+    #[test]
+    fn test_expand_self_reference() {
+        let (_, m) = parse_makefile("a = x $(a)\nb = $(c)\nc = $(b)\nd = $(a:x=y)\n").unwrap();
+        let sources = Arena::new();
+        let mut e = Evaluator::new(&sources);
+        e.evaluate(m).unwrap();
+        assert_eq!(
+            e.expand("$(a)"),
+            Err("recursive variable 'a' references itself".to_string())
+        );
+        assert_eq!(
+            e.expand("$(b)"),
+            Err("recursive variable 'b' references itself".to_string())
+        );
+        assert_eq!(
+            e.expand("$(d)"),
+            Err("recursive variable 'a' references itself".to_string())
+        );
+        assert_eq!(
+            e.expand("$(a"),
+            Err("unterminated variable reference".to_string())
+        );
+
+        // a simple variable expands the reference as soon as it is read
+        let (_, m) = parse_makefile(
+            "a = $(a) x
+b := $(a)
+",
+        )
+        .unwrap();
+        let res = Evaluator::new(&sources).evaluate(m);
+        assert_eq!(
+            res,
+            Err("2:1: recursive variable 'a' references itself".to_string())
+        );
+    }
+
+    #[test]
+    fn test_expand_flavours() {
+        let data = "A = $(X)\nB := $(X)\nX = 1\nA += $(X)\nB += $(X)\nC ?= $(X)\nC ?= 2\nD :=\nD ?= 3\nE ::= $$(X)\nX = 4\n";
+        let (_, m) = parse_makefile(data).unwrap();
+        let sources = Arena::new();
+        let mut e = Evaluator::new(&sources);
+        e.evaluate(m).unwrap();
+        // recursive variables see the last value of X, simple ones the value
+        // it had when they were assigned
+        assert_eq!(e.expand("$(A)").unwrap(), "4 4");
+        assert_eq!(e.expand("$(B)").unwrap(), "1");
+        assert_eq!(e.expand("$(C)").unwrap(), "4");
+        assert_eq!(e.expand("$(D)").unwrap(), "");
+        assert_eq!(e.expand("$(E)").unwrap(), "$(X)");
+    }
+
+    #[test]
+    fn test_expand_functions() {
+        let data = "SRCS = main.c util.c defs.h\nOBJS = $(patsubst %.c,%.o,$(filter %.c,$(SRCS)))\nLIBS := $(sort $(subst -l,,-lz -lm -lz))\nprog: $(OBJS) | $(SRCS:.c=.d)\n$(OBJS): %.o: %.c\n$(EMPTY): x\n";
+        let (_, m) = parse_makefile(data).unwrap();
+        let sources = Arena::new();
+        let mut e = Evaluator::new(&sources);
+        let m = e.evaluate(m).unwrap();
+        assert_eq!(e.expand("$(OBJS)").unwrap(), "main.o util.o");
+        assert_eq!(e.expand("$(LIBS)").unwrap(), "m z");
+        assert_eq!(
+            e.expand("$(words $(SRCS)) $(word 2,$(SRCS))").unwrap(),
+            "3 util.c"
+        );
+        assert_eq!(
+            e.expand("$(SRCS:%.h=%.hpp)").unwrap(),
+            "main.c util.c defs.hpp"
+        );
+
+        // targets and prerequisites are expanded when the rule is read
+        assert_eq!(targets(&m), vec!["prog", "main.o", "util.o"]);
+        let prog = m.rules()[0];
+        assert_eq!(
+            prog.prerequsities,
+            vec![
+                Prerequisite {
+                    name: "main.o",
+                    span: prog.prerequsities[0].span
+                },
+                Prerequisite {
+                    name: "util.o",
+                    span: prog.prerequsities[0].span
+                },
+            ]
+        );
+        assert_eq!(prog.order_only.len(), 3);
+        assert_eq!(prog.order_only[2], "defs.h");
+
+        let (_, m) = parse_makefile("all: $(word 0,a)\n").unwrap();
+        assert_eq!(
+            Evaluator::new(&sources).evaluate(m),
+            Err("1:1: first argument to 'word' function must be greater than 0".to_string())
+        );
+
+        // a guard such as $(error ...) must not be skipped quietly
+        let (_, m) = parse_makefile("X := $(error no compiler)\n").unwrap();
+        assert_eq!(
+            Evaluator::new(&sources).evaluate(m),
+            Err("1:1: function 'error' is not supported".to_string())
+        );
+    }
+
+    #[test]
+    fn test_expand_rule_kind() {
+        // a % in a reference doesn't make a pattern rule, but one in the
+        // expanded targets does
+        let data = "SRCS = a.c\nP = %.o\n$(patsubst %.c,%.o,$(SRCS)): defs.h\n\tcc\n$(SRCS:%.c=%.d) b.d: x\n$(P): %.c\n";
+        let (_, m) = parse_makefile(data).unwrap();
+        let sources = Arena::new();
+        let m = Evaluator::new(&sources).evaluate(m).unwrap();
+        let rules: Vec<(RuleKind, Vec<&str>)> = m
+            .rules()
+            .iter()
+            .map(|r| (r.kind, r.targets.clone()))
+            .collect();
+        assert_eq!(
+            rules,
+            vec![
+                (RuleKind::Explicit, vec!["a.o"]),
+                (RuleKind::Explicit, vec!["a.d", "b.d"]),
+                (RuleKind::Pattern, vec!["%.o"]),
+            ]
+        );
+
+        let data = "P = %.o\n$(P) foo.o: x\n";
+        let (_, m) = parse_makefile(data).unwrap();
+        assert_eq!(
+            Evaluator::new(&sources).evaluate(m),
+            Err("2:1: mixed implicit and normal rules".to_string())
+        );
+        let data = "P = foo.o\nfoo.o: $(P): %.c\n";
+        let (_, m) = parse_makefile(data).unwrap();
+        assert_eq!(
+            Evaluator::new(&sources).evaluate(m),
+            Err("2:1: target pattern contains no '%'".to_string())
+        );
+    }
+
+    #[test]
+    fn test_expand_wildcard() {
+        let data = "MKS := $(wildcard assets/*/c*.mk)\nall: $(notdir $(MKS:.mk=.o)) $(wildcard *.missing)\n";
+        let (_, m) = parse_makefile(data).unwrap();
+        let sources = Arena::new();
+        let mut e = Evaluator::new(&sources);
+        let m = e.evaluate(m).unwrap();
+        assert_eq!(
+            e.expand("$(MKS)").unwrap(),
+            "assets/includes/common.mk assets/includes/cycle-a.mk assets/includes/cycle-b.mk"
+        );
+        assert_eq!(
+            m.rules()[0].prerequsities,
+            vec!["common.o", "cycle-a.o", "cycle-b.o"]
+        );
+    }
+
+    #[test]
+    fn test_evaluate_ifeq() {
+        let data = "CC = gcc\nifeq ($(CC),gcc)\ngcc:\nelse\nother:\nendif\n";
+        let (_, m) = parse_makefile(data).unwrap();
+        let sources = Arena::new();
+        let m = Evaluator::new(&sources).evaluate(m).unwrap();
+        assert_eq!(targets(&m), vec!["gcc"]);
+    }
+
+    #[test]
+    fn test_evaluate_ifdef_empty() {
+        let data = "EMPTY =\nifdef EMPTY\ndefined:\nendif\nifndef EMPTY\nundefined:\nendif\n";
+        let (_, m) = parse_makefile(data).unwrap();
+        let sources = Arena::new();
+        let m = Evaluator::new(&sources).evaluate(m).unwrap();
+        assert_eq!(targets(&m), vec!["undefined"]);
+    }
+
+    #[test]
+    fn test_evaluate_else_chain() {
+        let data = "OS = linux\nifeq ($(OS),windows)\nwin:\nelse ifeq \"$(OS)\" \"linux\"\nlinux:\nelse\nother:\nendif\n";
+        let (_, m) = parse_makefile(data).unwrap();
+        let sources = Arena::new();
+        let m = Evaluator::new(&sources).evaluate(m).unwrap();
+        assert_eq!(targets(&m), vec!["linux"]);
+    }
+
+    #[test]
+    fn test_evaluate_recipe_in_conditional() {
+        let data = "X = 1\nall:\n\techo start\nifdef X\n\techo a\nelse\n\techo b\nendif\n";
+        let (_, m) = parse_makefile(data).unwrap();
+        let sources = Arena::new();
+        let m = Evaluator::new(&sources).evaluate(m).unwrap();
+        let recipe: Vec<&str> = m.rules()[0].recipe.iter().map(|l| l.command).collect();
+        assert_eq!(recipe, vec!["echo start", "echo a"]);
+
+        // the recipe of a rule that is ignored is ignored with it
+        let data = "$(NONE):\nifndef X\n\techo a\nendif\n";
+        let (_, m) = parse_makefile(data).unwrap();
+        let m = Evaluator::new(&sources).evaluate(m).unwrap();
+        assert!(m.statements.is_empty());
+
+        // an assignment in the branch that is taken ends the recipe
+        let data = "X = 1\nall:\nifdef X\nY = 1\nendif\n\techo a\n";
+        let (_, m) = parse_makefile(data).unwrap();
+        assert_eq!(
+            Evaluator::new(&sources).evaluate(m),
+            Err("6:2: recipe commences before first target".to_string())
+        );
+    }
+
+    #[test]
+    fn test_evaluate_recipe_prefix() {
+        let sources = Arena::new();
+        let evaluate = |data| {
+            let (_, m) = parse_makefile(data).unwrap();
+            Evaluator::new(&sources)
+                .evaluate(m)
+                .map(|m| m.statements.len())
+        };
+        assert_eq!(
+            evaluate("ifdef NOPE\n.RECIPEPREFIX = >\nendif\nall:\n\techo hi\n"),
+            Ok(1)
+        );
+        assert_eq!(
+            evaluate(".RECIPEPREFIX = >\nifndef NOPE\n.RECIPEPREFIX = >\nendif\n"),
+            Ok(2)
+        );
+        let unsupported = "setting .RECIPEPREFIX inside a conditional or from a reference is only supported when reading a file";
+        assert_eq!(
+            evaluate("ifndef NOPE\n.RECIPEPREFIX = >\nendif\n"),
+            Err(format!("2:1: {}", unsupported))
+        );
+        assert_eq!(
+            evaluate("P = >\n.RECIPEPREFIX = $(P)\n"),
+            Err(format!("2:1: {}", unsupported))
+        );
+    }
+
+    #[test]
+    fn test_evaluate_file_recipe_prefix() {
+        let sources = Arena::new();
+        let m = Evaluator::new(&sources)
+            .evaluate_file(Path::new("./assets/includes/recipe-prefix.mk"))
+            .unwrap();
+        let rules = m.rules();
+        assert_eq!(targets(&m), vec!["all", "hello"]);
+        assert_eq!(rules[0].recipe[0].command, "echo all");
+        assert_eq!(rules[1].recipe[0].command, "echo hello");
+    }
+
+    #[test]
+    fn test_evaluate_uses_file_order() {
+        let data = "ifdef LATER\nearly:\nendif\nLATER = 1\nifdef LATER\nlate:\nendif\n";
+        let (_, m) = parse_makefile(data).unwrap();
+        let sources = Arena::new();
+        let m = Evaluator::new(&sources).evaluate(m).unwrap();
+        assert_eq!(targets(&m), vec!["late"]);
+    }
+
+    #[test]
+    fn test_evaluate_nested() {
+        let data =
+            "A = 1\nifdef A\nifneq ($(B),)\nboth:\nelse\nB += x\nendif\nendif\nifeq ($(B),x)\nb:\nendif\n";
+        let (_, m) = parse_makefile(data).unwrap();
+        let sources = Arena::new();
+        let m = Evaluator::new(&sources).evaluate(m).unwrap();
+        assert_eq!(targets(&m), vec!["b"]);
+        assert_eq!(m.variables().len(), 2);
+    }
+
+    #[test]
+    fn test_evaluate_include() {
+        let sources = Arena::new();
+        let mut e = Evaluator::new(&sources);
+        e.include_dirs(vec![PathBuf::from("./assets/includes")]);
+        let m = e
+            .evaluate_file(Path::new("./assets/includes/main.mk"))
+            .unwrap();
+        assert_eq!(targets(&m), vec!["hello", "all"]);
+        assert_eq!(e.expand("$(PROGRAM)").unwrap(), "hello");
+        // spans point into the file each rule was read from
+        let rules = m.rules();
+        assert_eq!(rules[0].span.to_string(), "./assets/includes/common.mk:4:1");
+        assert_eq!(
+            rules[1].prerequsities[0].span.to_string(),
+            "./assets/includes/main.mk:6:6"
+        );
+    }
+
+    #[test]
+    fn test_evaluate_include_missing() {
+        let (_, m) = parse_makefile("include missing.mk\n").unwrap();
+        let sources = Arena::new();
+        let res = Evaluator::new(&sources).evaluate(m);
+        assert_eq!(
+            res,
+            Err("1:1: missing.mk: No such file or directory".to_string())
+        );
+    }
+
+    #[test]
+    fn test_evaluate_include_cycle() {
+        let sources = Arena::new();
+        let res = Evaluator::new(&sources).evaluate_file(Path::new("./assets/includes/cycle-a.mk"));
+        match res {
+            Err(e) => {
+                assert!(e.starts_with("include cycle: "));
+                assert!(e.ends_with("cycle-a.mk"));
+            }
+            Ok(_) => panic!("expected an include cycle"),
+        }
+    }
+
+    #[test]
+    fn test_evaluate_define() {
+        let data = "define greet\n@echo hello\n@echo $(1)\nendef\nX := $(greet)\nundefine greet\n";
+        let (_, m) = parse_makefile(data).unwrap();
+        let sources = Arena::new();
+        let mut e = Evaluator::new(&sources);
+        e.evaluate(m).unwrap();
+        assert_eq!(e.expand("$(X)").unwrap(), "@echo hello\n@echo ");
+        assert_eq!(e.expand("$(greet)").unwrap(), "");
+        assert!(!e.is_defined("greet"));
+    }
+
+    #[test]
+    fn test_evaluate_override() {
+        let data = "CFLAGS = -O2\nLIBS = -lm\noverride LIBS += -lz\nLDFLAGS = -g\n";
+        let (_, m) = parse_makefile(data).unwrap();
+        let sources = Arena::new();
+        let mut e = Evaluator::new(&sources);
+        e.define_command_line("CFLAGS=-O0").unwrap();
+        e.define_command_line("LIBS := -lc").unwrap();
+        e.define_environment(vec![("LDFLAGS".to_string(), "-s".to_string())]);
+        e.evaluate(m).unwrap();
+        assert_eq!(e.expand("$(CFLAGS)").unwrap(), "-O0");
+        assert_eq!(e.expand("$(LIBS)").unwrap(), "-lc -lz");
+        assert_eq!(e.expand("$(LDFLAGS)").unwrap(), "-g");
+        assert!(e.define_command_line("all").is_err());
+    }
+
+    #[test]
+    fn test_evaluate_undefine_command_line() {
+        let data = "undefine A\noverride undefine B\n";
+        let (_, m) = parse_makefile(data).unwrap();
+        let sources = Arena::new();
+        let mut e = Evaluator::new(&sources);
+        e.define_command_line("A=1").unwrap();
+        e.define_command_line("B=1").unwrap();
+        e.evaluate(m).unwrap();
+        assert!(e.is_defined("A"));
+        assert!(!e.is_defined("B"));
+    }
+
+    #[test]
+    fn test_environment() {
+        let data = "export CC = clang\nCFLAGS = -g\nprivate export SECRET = x\nunexport HOME\nexport EARLY\nEARLY = $(CC)\n";
+        let (_, m) = parse_makefile(data).unwrap();
+        let sources = Arena::new();
+        let mut e = Evaluator::new(&sources);
+        e.define_environment(vec![
+            ("HOME".to_string(), "/root".to_string()),
+            ("USER".to_string(), "root".to_string()),
+        ]);
+        e.evaluate(m).unwrap();
+        assert_eq!(
+            e.environment().unwrap(),
+            vec![
+                ("CC".to_string(), "clang".to_string()),
+                ("EARLY".to_string(), "clang".to_string()),
+                ("USER".to_string(), "root".to_string()),
+            ]
+        );
+
+        let (_, m) = parse_makefile("export\nunexport B\nA = 1\nB = 2\nC.D = 3\n").unwrap();
+        let mut e = Evaluator::new(&sources);
+        e.evaluate(m).unwrap();
+        assert_eq!(
+            e.environment().unwrap(),
+            vec![("A".to_string(), "1".to_string())]
+        );
+    }
+
+    #[test]
+    fn test_target_variables() {
+        let data = "CFLAGS = -O2\nLIB = $(CFLAGS)\nX := global\nprog: CFLAGS += -g\nprog: private SECRET = x\n%.o: OPT := $(X)\nsrc/%.o: OPT = -O1\nX := later\nprog: main.o\n";
+        let (_, m) = parse_makefile(data).unwrap();
+        let sources = Arena::new();
+        let mut e = Evaluator::new(&sources);
+        e.define_command_line("CC=gcc").unwrap();
+        let m = e.evaluate(m).unwrap();
+        assert_eq!(m.target_variables().len(), 4);
+        assert_eq!(e.expand("$(CFLAGS) $(SECRET)").unwrap(), "-O2 ");
+
+        let prog = e.scope("prog").unwrap();
+        assert_eq!(prog.expand("$(CFLAGS)").unwrap(), "-O2 -g");
+        assert_eq!(prog.expand("$(LIB)").unwrap(), "-O2 -g");
+        assert_eq!(prog.expand("$(SECRET)").unwrap(), "x");
+
+        // prerequisites inherit everything but private variables
+        let main = prog.scope("main.o").unwrap();
+        assert_eq!(main.expand("$(CFLAGS) $(SECRET)").unwrap(), "-O2 -g ");
+        assert_eq!(main.expand("$(OPT)").unwrap(), "global");
+        assert_eq!(
+            e.scope("main.o").unwrap().expand("$(CFLAGS)").unwrap(),
+            "-O2"
+        );
+
+        // the pattern with the shorter stem is more specific
+        assert_eq!(
+            e.scope("src/main.o").unwrap().expand("$(OPT)").unwrap(),
+            "-O1"
+        );
+        assert_eq!(e.scope("main.c").unwrap().expand("$(OPT)").unwrap(), "");
+    }
+
+    #[test]
+    fn test_private_global_variables() {
+        let data = "private A = 1\nB = 2\nall: private B = 3\nall: C = 4\n";
+        let (_, m) = parse_makefile(data).unwrap();
+        let sources = Arena::new();
+        let mut e = Evaluator::new(&sources);
+        e.define_command_line("C=cli").unwrap();
+        e.evaluate(m).unwrap();
+        assert_eq!(e.expand("$(A) $(B)").unwrap(), "1 2");
+        let all = e.scope("all").unwrap();
+        assert_eq!(all.expand("$(A) $(B) $(C)").unwrap(), " 3 cli");
+        assert_eq!(
+            all.scope("dep").unwrap().expand("$(A) $(B) $(C)").unwrap(),
+            " 2 cli"
+        );
+    }
+
+    #[test]
+    fn test_vpath() {
+        let data = "INC = include\nvpath %.h $(INC):gen\nvpath %.c lib\nvpath %.c\nVPATH = src\n";
+        let (_, m) = parse_makefile(data).unwrap();
+        let sources = Arena::new();
+        let mut e = Evaluator::new(&sources);
+        e.evaluate(m).unwrap();
+        let search = e.search().unwrap();
+        let exists = |p: &str| p == "gen/defs.h" || p == "src/main.c" || p == "lib/util.c";
+        assert_eq!(
+            search.find("defs.h", exists),
+            Some("gen/defs.h".to_string())
+        );
+        assert_eq!(
+            search.find("main.c", exists),
+            Some("src/main.c".to_string())
+        );
+        assert_eq!(search.find("util.c", exists), None);
+
+        e.define_automatic(vec![
+            ("@".to_string(), "main.o".to_string()),
+            ("<".to_string(), "src/main.c".to_string()),
+        ]);
+        assert_eq!(
+            e.expand("cc -c $< -o $@").unwrap(),
+            "cc -c src/main.c -o main.o"
+        );
+    }
+
+    #[test]
+    fn test_escaped_comment() {
+        let data = "HASH = \\# # comment\nURL = http://host/page\\#top\n";
+        let (_, m) = parse_makefile(data).unwrap();
+        let sources = Arena::new();
+        let mut e = Evaluator::new(&sources);
+        e.evaluate(m).unwrap();
+        assert_eq!(
+            e.expand("$(HASH)|$(URL)").unwrap(),
+            "#|http://host/page#top"
+        );
+
+        // backslashes before a # are halved, and an even number of them
+        // doesn't escape it
+        let data = "TWO = a\\\\#b\nTHREE = a\\\\\\#b\nDIR = c:\\dir\\a\n";
+        let (_, m) = parse_makefile(data).unwrap();
+        let mut e = Evaluator::new(&sources);
+        e.evaluate(m).unwrap();
+        assert_eq!(e.expand("$(TWO)").unwrap(), "a\\");
+        assert_eq!(e.expand("$(THREE)").unwrap(), "a\\#b");
+        assert_eq!(e.expand("$(DIR)").unwrap(), "c:\\dir\\a");
+    }
+}
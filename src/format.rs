@@ -0,0 +1,517 @@
+use nom::{character::complete::space0, sequence::preceded};
+
+use crate::parser::{
+    find_comment, find_inline_recipe, parse_assignment_op, parse_conditional_keyword,
+    parse_recovering, parse_statement, State,
+};
+use crate::types::*;
+
+// Lines are wrapped once they would be longer than WIDTH, and the lines they
+// continue onto are indented by INDENT
+const WIDTH: usize = 80;
+const INDENT: &str = "    ";
+
+// format rewrites the makefile read from file in the canonical style.
+// Comments, recipes and define blocks are kept as they are written, while
+// variable definitions, rules and directives are rewritten from what the
+// parser found in them. A makefile that doesn't parse is left alone and the
+// problems found in it are returned.
+pub fn format<'a>(file: &'a str, source: &'a str) -> Result<String, Vec<Diagnostic<'a>>> {
+    let (_, diagnostics) = parse_recovering(file, source);
+    if !diagnostics.is_empty() {
+        return Err(diagnostics);
+    }
+
+    let mut f = Formatter::default();
+    let mut state = State::new(file, source);
+    let mut i = source;
+    while !i.is_empty() {
+        let (line, rest) = split_line(i);
+        // in a recipe, anything after the prefix is passed to the shell
+        let recipe = state.recipe && line.starts_with(state.prefix);
+        if line.trim().is_empty() && !recipe {
+            f.blank();
+            i = rest;
+            continue;
+        }
+        if line.trim_start().starts_with('#') && !recipe {
+            f.line(line.trim().to_string());
+            i = rest;
+            continue;
+        }
+        if let (Ok((condition, keyword)), false) = (parse_conditional_keyword(line), recipe) {
+            state.conditional_line(keyword, condition);
+            f.line(directive(line.trim()));
+            i = rest;
+            continue;
+        }
+        let (j, statement) =
+            parse_statement(i, &mut state).map_err(|e| vec![state.diagnostic(i, e)])?;
+        let raw = lines(&i[..i.len() - j.len()]);
+        let used = match statement {
+            Statement::Variable(v) if v.define => f.verbatim(&raw),
+            // with a space after it a variable named ifdef or else would be
+            // read as a conditional
+            Statement::Variable(v) if parse_conditional_keyword(v.name).is_ok() => f.verbatim(&raw),
+            Statement::Variable(v) => f.variable(&v, &raw),
+            Statement::TargetVariable(v) if v.variable.define => f.verbatim(&raw),
+            Statement::TargetVariable(v) => f.target_variable(&v, &raw),
+            Statement::Rule(r) => f.rule(&r, &raw, state.prefix),
+            Statement::Recipe(_) => f.recipe(&raw, 0, false, state.prefix),
+            Statement::Include(inc) => {
+                let name = raw[0].split_whitespace().next().unwrap_or("include");
+                f.directive(name, &inc.names, raw[0])
+            }
+            Statement::Export(e) => {
+                let name = if e.export { "export" } else { "unexport" };
+                f.directive(name, &e.names, raw[0])
+            }
+            Statement::Undefine(u) => {
+                let name = if u.r#override {
+                    "override undefine"
+                } else {
+                    "undefine"
+                };
+                f.directive(name, &[u.name], raw[0])
+            }
+            Statement::Vpath(v) => {
+                let words: Vec<&str> = v.pattern.iter().chain(&v.directories).copied().collect();
+                f.directive("vpath", &words, raw[0])
+            }
+            // conditionals are formatted a line at a time above
+            Statement::Conditional(_) => raw.len(),
+        };
+        // the blank lines the parser took after the statement
+        if raw[used..].iter().any(|l| l.trim().is_empty()) {
+            f.blank();
+        }
+        i = j;
+    }
+    Ok(f.finish())
+}
+
+// split_line returns the first line of i, without its line ending, and the rest
+fn split_line(i: &str) -> (&str, &str) {
+    match i.find('\n') {
+        Some(idx) => (i[..idx].trim_end_matches('\r'), &i[idx + 1..]),
+        None => (i, ""),
+    }
+}
+
+fn lines(i: &str) -> Vec<&str> {
+    i.lines().collect()
+}
+
+// directive puts a single space between a conditional directive and its arguments
+fn directive(line: &str) -> String {
+    match line.split_once([' ', '\t']) {
+        Some(("else", rest)) => format!("else {}", directive(rest.trim_start())),
+        Some((name, rest)) => format!("{} {}", name, rest.trim_start()),
+        None => line.to_string(),
+    }
+}
+
+// comment returns the comment at the end of line, if there is one
+fn comment(line: &str) -> Option<&str> {
+    find_comment(line).map(|idx| &line[idx..])
+}
+
+// assigned adds a value after an operator. Any whitespace at the end of the
+// value is part of it, so it is kept.
+fn assigned(line: String, value: &str) -> String {
+    if value.is_empty() {
+        line
+    } else {
+        format!("{} {}", line, value)
+    }
+}
+
+fn operator(assignment: Assignment) -> &'static str {
+    match assignment {
+        Assignment::Recursive => "=",
+        Assignment::Simple => ":=",
+        Assignment::Conditional => "?=",
+        Assignment::Append => "+=",
+        Assignment::Shell => "!=",
+    }
+}
+
+// Pending is a single line variable definition waiting to be aligned with
+// the definitions around it
+struct Pending {
+    name: String,
+    operator: &'static str,
+    value: String,
+}
+
+#[derive(Default)]
+struct Formatter {
+    out: Vec<String>,
+    aligned: Vec<Pending>,
+}
+
+impl Formatter {
+    fn line(&mut self, line: String) {
+        self.flush();
+        self.out.push(line);
+    }
+
+    // blank adds a blank line, unless there already is one
+    fn blank(&mut self) {
+        self.flush();
+        if self.out.last().is_some_and(|l| !l.is_empty()) {
+            self.out.push(String::new());
+        }
+    }
+
+    // flush writes out the pending definitions with their operators lined up
+    fn flush(&mut self) {
+        let width = self
+            .aligned
+            .iter()
+            .map(|a| a.name.chars().count())
+            .max()
+            .unwrap_or(0);
+        for a in self.aligned.drain(..) {
+            let pad = width - a.name.chars().count();
+            let line = format!("{}{} {}", a.name, " ".repeat(pad), a.operator);
+            self.out.push(assigned(line, &a.value));
+        }
+    }
+
+    fn finish(mut self) -> String {
+        self.flush();
+        while self.out.last().is_some_and(|l| l.is_empty()) {
+            self.out.pop();
+        }
+        let mut res = self.out.join("\n");
+        res.push('\n');
+        res
+    }
+
+    // verbatim writes the lines of a statement as they are, returning how many
+    // it used
+    fn verbatim(&mut self, raw: &[&str]) -> usize {
+        let used = raw.len() - raw.iter().rev().take_while(|l| l.trim().is_empty()).count();
+        for l in &raw[..used] {
+            self.line(l.to_string());
+        }
+        used
+    }
+
+    fn variable(&mut self, v: &Variable, raw: &[&str]) -> usize {
+        let (name, value, used) = definition(v, raw);
+        if used == 1 {
+            self.aligned.push(Pending {
+                name,
+                operator: operator(v.assignment),
+                value: value[0].to_string(),
+            });
+        } else {
+            let line = format!("{} {}", name, operator(v.assignment));
+            self.continued(assigned(line, value[0]), &value[1..]);
+        }
+        used
+    }
+
+    fn target_variable(&mut self, v: &TargetVariable, raw: &[&str]) -> usize {
+        let (name, value, used) = definition(&v.variable, raw);
+        // the colon is written right after the last target
+        let last = v.targets[v.targets.len() - 1];
+        let end = last.as_ptr() as usize - raw[0].as_ptr() as usize + last.len();
+        let delimiter = if raw[0][end..].trim_start().starts_with("::") {
+            "::"
+        } else {
+            ":"
+        };
+        let line = format!(
+            "{}{} {} {}",
+            v.targets.join(" "),
+            delimiter,
+            name,
+            operator(v.variable.assignment),
+        );
+        self.continued(assigned(line, value[0]), &value[1..]);
+        used
+    }
+
+    // continued writes line and the lines continuing it, each indented the same way
+    fn continued(&mut self, line: String, rest: &[&str]) {
+        self.line(line);
+        for l in rest {
+            self.out.push(format!("{}{}", INDENT, l));
+        }
+    }
+
+    fn rule(&mut self, r: &Rule, raw: &[&str], prefix: char) -> usize {
+        // the rule line may be continued onto several lines, up to a comment
+        // or an inline recipe
+        let mut used = 0;
+        let mut inline = None;
+        for l in raw {
+            used += 1;
+            let code = find_comment(l).map_or(*l, |idx| &l[..idx]);
+            if let Some(idx) = find_inline_recipe(code) {
+                inline = Some(l[idx + 1..].trim_start());
+                break;
+            }
+            if code.len() < l.len() || !l.ends_with('\\') {
+                break;
+            }
+        }
+
+        let delimiter = match (r.grouped, r.double_colon) {
+            (true, true) => "&::",
+            (true, false) => "&:",
+            (false, true) => "::",
+            (false, false) => ":",
+        };
+        let mut words = Vec::new();
+        if let RuleKind::StaticPattern(pattern) = r.kind {
+            words.push(format!("{}:", pattern));
+        }
+        words.extend(r.prerequsities.iter().map(|p| p.name.to_string()));
+        if !r.order_only.is_empty() {
+            words.push("|".to_string());
+            words.extend(r.order_only.iter().map(|p| p.name.to_string()));
+        }
+
+        let mut header = vec![format!("{}{}", r.targets.join(" "), delimiter)];
+        for (n, word) in words.iter().enumerate() {
+            let last = header.last_mut().unwrap();
+            if n > 0 && last.chars().count() + 1 + word.chars().count() > WIDTH {
+                last.push_str(" \\");
+                header.push(format!("{}{}", INDENT, word));
+            } else {
+                last.push(' ');
+                last.push_str(word);
+            }
+        }
+        let last = header.last_mut().unwrap();
+        match inline {
+            Some(inline) => {
+                last.push_str(" ; ");
+                last.push_str(inline);
+            }
+            None => {
+                if let Some(comment) = comment(raw[used - 1]) {
+                    last.push(' ');
+                    last.push_str(comment.trim_end());
+                }
+            }
+        }
+        // a name ending in a backslash can't go at the end of a line, where it
+        // would continue the line instead
+        if header.last().is_some_and(|l| l.ends_with('\\')) {
+            header = raw[..used].iter().map(|l| l.to_string()).collect();
+        }
+        for line in header {
+            self.line(line);
+        }
+
+        let continued = inline.is_some_and(|l| l.ends_with('\\'));
+        self.recipe(raw, used, continued, prefix)
+    }
+
+    // recipe writes the recipe lines in raw from start, keeping them as they
+    // are along with the lines continuing them. It returns the number of
+    // lines used, leaving any blank lines at the end.
+    fn recipe(&mut self, raw: &[&str], start: usize, continued: bool, prefix: char) -> usize {
+        let mut continued = continued;
+        let recipe = raw.len() - raw.iter().rev().take_while(|l| l.trim().is_empty()).count();
+        let recipe = recipe.max(start);
+        for l in &raw[start..recipe] {
+            if continued || l.starts_with(prefix) {
+                self.line(l.to_string());
+                continued = l.ends_with('\\');
+            } else if l.trim().is_empty() {
+                self.blank();
+            } else {
+                self.line(l.trim().to_string());
+            }
+        }
+        recipe
+    }
+
+    // directive writes a directive followed by its words and any comment
+    fn directive(&mut self, name: &str, words: &[&str], line: &str) -> usize {
+        let mut res = name.to_string();
+        for w in words {
+            res.push(' ');
+            res.push_str(w);
+        }
+        if let Some(comment) = comment(line) {
+            res.push(' ');
+            res.push_str(comment.trim_end());
+        }
+        self.line(res);
+        1
+    }
+}
+
+// definition returns the modifiers and name of a variable, the lines of its
+// value as written and how many lines of raw it takes
+fn definition<'a>(v: &Variable<'a>, raw: &[&'a str]) -> (String, Vec<&'a str>, usize) {
+    let mut name = String::new();
+    if v.modifiers.export {
+        name.push_str("export ");
+    }
+    if v.modifiers.r#override {
+        name.push_str("override ");
+    }
+    if v.modifiers.private {
+        name.push_str("private ");
+    }
+    name.push_str(v.name);
+
+    // the value starts after the operator following the name
+    let line = raw
+        .iter()
+        .find(|l| {
+            let start = l.as_ptr() as usize;
+            (start..=start + l.len()).contains(&(v.name.as_ptr() as usize))
+        })
+        .copied()
+        .unwrap_or(raw[0]);
+    let end = v.name.as_ptr() as usize - line.as_ptr() as usize + v.name.len();
+    let first = match preceded(space0, parse_assignment_op)(&line[end..]) {
+        Ok((rest, _)) => rest.trim_start_matches([' ', '\t']),
+        Err(_) => "",
+    };
+    let mut value = vec![first];
+    let mut used = 1;
+    let mut continued = first.ends_with('\\');
+    while continued && used < raw.len() {
+        let l = raw[used].trim_start_matches([' ', '\t']);
+        value.push(l);
+        used += 1;
+        continued = l.ends_with('\\');
+    }
+    (name, value, used)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::parser::parse_makefile;
+    use std::fs;
+
+    // summary lists what each statement means, leaving out how it was written
+    fn summary(statements: &[Statement]) -> Vec<String> {
+        let words = |v: &[Prerequisite]| v.iter().map(|p| p.name).collect::<Vec<&str>>().join(" ");
+        let mut res = Vec::new();
+        for s in statements {
+            match s {
+                Statement::Variable(v) => res.push(format!(
+                    "{:?} {} {:?} {}",
+                    v.modifiers,
+                    v.name,
+                    v.assignment,
+                    v.value
+                        .iter()
+                        .map(|p| p.trim_end_matches('\\').trim())
+                        .collect::<Vec<_>>()
+                        .join(" ")
+                )),
+                Statement::Rule(r) => {
+                    res.push(format!(
+                        "{:?} {} {} | {} {} {}",
+                        r.kind,
+                        r.targets.join(" "),
+                        words(&r.prerequsities),
+                        words(&r.order_only),
+                        r.double_colon,
+                        r.grouped
+                    ));
+                    res.extend(r.recipe.iter().map(|l| {
+                        format!("{:?}", (l.command, l.silent, l.ignore_errors, l.always))
+                    }));
+                }
+                Statement::Conditional(c) => {
+                    res.push(format!("{:?}", c.condition));
+                    res.extend(summary(&c.then));
+                    res.push("else".to_string());
+                    res.extend(summary(&c.otherwise));
+                }
+                s => res.push(format!("{:?}", s).split("span").next().unwrap().to_string()),
+            }
+        }
+        res
+    }
+
+    #[test]
+    fn test_format() {
+        let data = "# settings\nCC=gcc\nCFLAGS   :=  -O2 -g   # optimise\nexport   PREFIX ?= /usr/local\n\n\n\nSRCS = main.c \\\n\t\tutil.c \\\n  parse.c\n  ifdef  DEBUG\ninclude   debug.mk\nendif\nall   :  prog |   bin\nprog:main.o util.o parse.o lexer.o scanner.o token.o ast.o eval.o graph.o format.o types.o # link\n\t$(CC) -o $@ $^\n\n\n\t@echo done\n%.o : %.c ; $(CC) -c $<\ndebug:CFLAGS+=-O0\n";
+        let expected = "# settings
+CC            = gcc
+CFLAGS        := -O2 -g   # optimise
+export PREFIX ?= /usr/local
+
+SRCS = main.c \\
+    util.c \\
+    parse.c
+ifdef DEBUG
+include debug.mk
+endif
+all: prog | bin
+prog: main.o util.o parse.o lexer.o scanner.o token.o ast.o eval.o graph.o \\
+    format.o types.o # link
+\t$(CC) -o $@ $^
+
+\t@echo done
+%.o: %.c ; $(CC) -c $<
+debug: CFLAGS += -O0
+";
+        assert_eq!(format("", data), Ok(expected.to_string()));
+        assert_eq!(format("", expected), Ok(expected.to_string()));
+    }
+
+    #[test]
+    fn test_format_references() {
+        // a ; inside a reference doesn't start an inline recipe
+        let data = "all: $(shell echo a;echo b)\nb:   ${x;y}  c;echo d\n";
+        let expected = "all: $(shell echo a;echo b)\nb: ${x;y} c ; echo d\n";
+        assert_eq!(format("", data), Ok(expected.to_string()));
+        assert_eq!(format("", expected), Ok(expected.to_string()));
+    }
+
+    #[test]
+    fn test_format_recipe_in_conditional() {
+        let data = "X = 1\nall:\nifdef X\n\techo a\nelse\n\techo b\nendif\nb:\n\tifeq ($(X),1)\n\t  echo x\n\tendif\n";
+        assert_eq!(format("", data), Ok(data.to_string()));
+    }
+
+    #[test]
+    fn test_format_errors() {
+        let res = format("Makefile", "all: foo\n  echo foo\n");
+        let messages: Vec<String> = res.unwrap_err().iter().map(|d| d.to_string()).collect();
+        assert_eq!(
+            messages,
+            vec!["Makefile:2:3: recipe line starts with spaces, did you mean a tab?"]
+        );
+    }
+
+    #[test]
+    fn test_format_assets() {
+        for name in &["01-simple.mk", "02-complex.mk", "03-conditionals.mk"] {
+            let data = fs::read_to_string(format!("assets/{}", name)).unwrap();
+            let formatted = format(name, &data).unwrap();
+            // formatting again changes nothing
+            assert_eq!(
+                format(name, &formatted).as_ref(),
+                Ok(&formatted),
+                "{}",
+                name
+            );
+            // and the meaning is unchanged
+            let (_, before) = parse_makefile(&data).unwrap();
+            let (_, after) = parse_makefile(&formatted).unwrap();
+            assert_eq!(
+                summary(&before.statements),
+                summary(&after.statements),
+                "{}",
+                name
+            );
+        }
+    }
+}
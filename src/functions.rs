@@ -0,0 +1,518 @@
+// The builtin functions of GNU make that work on text and file names. Each
+// one is given its arguments after they have been expanded.
+use std::env;
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+
+use crate::pattern;
+
+fn words(text: &str) -> Vec<&str> {
+    text.split_whitespace().collect()
+}
+
+// each applies f to every word of text, leaving out those it returns None for
+fn each<'a, F: Fn(&'a str) -> Option<String>>(text: &'a str, f: F) -> String {
+    words(text)
+        .into_iter()
+        .filter_map(f)
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+// call runs the function with the given arguments. Functions that aren't
+// supported, such as shell or foreach, are an error rather than silently
+// expanding to nothing.
+pub fn call(function: &str, args: &[String]) -> Result<String, String> {
+    let required = match function {
+        "subst" | "patsubst" | "wordlist" => 3,
+        "findstring" | "filter" | "filter-out" | "word" | "addsuffix" | "addprefix" | "join" => 2,
+        _ => 1,
+    };
+    if args.len() < required {
+        return Err(format!(
+            "insufficient number of arguments ({}) to function '{}'",
+            args.len(),
+            function
+        ));
+    }
+    let arg = |n: usize| args[n].as_str();
+    let res = match function {
+        "subst" => subst(arg(0), arg(1), arg(2)),
+        "patsubst" => patsubst(arg(0), arg(1), arg(2)),
+        "strip" => words(arg(0)).join(" "),
+        "findstring" => findstring(arg(0), arg(1)).to_string(),
+        "filter" => filter(arg(0), arg(1), true),
+        "filter-out" => filter(arg(0), arg(1), false),
+        "sort" => sort(arg(0)),
+        "word" => word(arg(0), arg(1))?.to_string(),
+        "wordlist" => wordlist(arg(0), arg(1), arg(2))?,
+        "words" => words(arg(0)).len().to_string(),
+        "firstword" => words(arg(0)).first().copied().unwrap_or("").to_string(),
+        "lastword" => words(arg(0)).last().copied().unwrap_or("").to_string(),
+        "dir" => each(arg(0), |w| Some(dir(w).to_string())),
+        "notdir" => each(arg(0), |w| Some(notdir(w).to_string())),
+        "suffix" => each(arg(0), |w| suffix(w).map(String::from)),
+        "basename" => each(arg(0), |w| Some(basename(w).to_string())),
+        "addsuffix" => each(arg(1), |w| Some(format!("{}{}", w, arg(0)))),
+        "addprefix" => each(arg(1), |w| Some(format!("{}{}", arg(0), w))),
+        "join" => join(arg(0), arg(1)),
+        "wildcard" => each(arg(0), |w| {
+            Some(wildcard(w).join(" ")).filter(|w| !w.is_empty())
+        }),
+        "realpath" => each(arg(0), realpath),
+        "abspath" => each(arg(0), |w| Some(abspath(w))),
+        _ => return Err(format!("function '{}' is not supported", function)),
+    };
+    Ok(res)
+}
+
+// subst replaces every occurrence of from in text. An empty from matches
+// only at the end.
+pub fn subst(from: &str, to: &str, text: &str) -> String {
+    if from.is_empty() {
+        return format!("{}{}", text, to);
+    }
+    text.replace(from, to)
+}
+
+// patsubst replaces the words of text that match pattern. The first % in
+// replacement is replaced by the text the % in pattern matched.
+pub fn patsubst(pattern: &str, replacement: &str, text: &str) -> String {
+    words(text)
+        .into_iter()
+        .map(|w| match pattern::stem(pattern, w) {
+            Some(stem) if pattern.contains('%') => pattern::substitute(replacement, stem),
+            Some(_) => replacement.to_string(),
+            None => w.to_string(),
+        })
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+// substitution is a substitution reference, $(VAR:from=to). Without a % it
+// replaces from at the end of each word, like patsubst with %from and %to.
+pub fn substitution(text: &str, from: &str, to: &str) -> String {
+    if from.contains('%') {
+        patsubst(from, to, text)
+    } else {
+        patsubst(&format!("%{}", from), &format!("%{}", to), text)
+    }
+}
+
+pub fn findstring<'a>(find: &'a str, text: &str) -> &'a str {
+    if text.contains(find) {
+        find
+    } else {
+        ""
+    }
+}
+
+// filter keeps the words of text that match any of patterns, or with keep
+// false removes them
+pub fn filter(patterns: &str, text: &str, keep: bool) -> String {
+    let patterns = words(patterns);
+    words(text)
+        .into_iter()
+        .filter(|w| patterns.iter().any(|p| pattern::stem(p, w).is_some()) == keep)
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+// sort sorts the words of text and removes duplicates
+pub fn sort(text: &str) -> String {
+    let mut res = words(text);
+    res.sort_unstable();
+    res.dedup();
+    res.join(" ")
+}
+
+fn number(ordinal: &str, function: &str, arg: &str) -> Result<usize, String> {
+    arg.trim().parse().map_err(|_| {
+        format!(
+            "non-numeric {} argument to '{}' function: '{}'",
+            ordinal,
+            function,
+            arg.trim()
+        )
+    })
+}
+
+// word returns the nth word of text, counting from 1
+pub fn word<'a>(n: &str, text: &'a str) -> Result<&'a str, String> {
+    match number("first", "word", n)? {
+        0 => Err("first argument to 'word' function must be greater than 0".to_string()),
+        n => Ok(words(text).get(n - 1).copied().unwrap_or("")),
+    }
+}
+
+// wordlist returns the words of text from start to end, inclusive
+pub fn wordlist(start: &str, end: &str, text: &str) -> Result<String, String> {
+    let start = match number("first", "wordlist", start)? {
+        0 => {
+            return Err("invalid first argument to 'wordlist' function: '0'".to_string());
+        }
+        n => n,
+    };
+    let end = number("second", "wordlist", end)?;
+    Ok(words(text)
+        .into_iter()
+        .skip(start - 1)
+        .take(end.saturating_sub(start - 1))
+        .collect::<Vec<_>>()
+        .join(" "))
+}
+
+// dir returns the directory part of name, up to and including the last
+// slash, or ./ if it has none
+pub fn dir(name: &str) -> &str {
+    match name.rfind('/') {
+        Some(idx) => &name[..=idx],
+        None => "./",
+    }
+}
+
+// notdir returns everything after the last slash in name
+pub fn notdir(name: &str) -> &str {
+    &name[name.rfind('/').map_or(0, |idx| idx + 1)..]
+}
+
+// suffix returns the part of the file name from its last dot, if it has one
+pub fn suffix(name: &str) -> Option<&str> {
+    let file = notdir(name);
+    file.rfind('.').map(|idx| &file[idx..])
+}
+
+// basename returns name without its suffix
+pub fn basename(name: &str) -> &str {
+    match suffix(name) {
+        Some(suffix) => &name[..name.len() - suffix.len()],
+        None => name,
+    }
+}
+
+// join joins the words of a and b pairwise. Words left over in the longer
+// list are kept as they are.
+pub fn join(a: &str, b: &str) -> String {
+    let (a, b) = (words(a), words(b));
+    (0..a.len().max(b.len()))
+        .map(|i| format!("{}{}", a.get(i).unwrap_or(&""), b.get(i).unwrap_or(&"")))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+// class matches c against the character set at the start of p, which follows
+// a [. It returns whether c is in the set and the length of the set
+// including the closing ], or None if the set isn't closed.
+fn class(p: &[char], c: char) -> Option<(bool, usize)> {
+    let negate = matches!(p.first(), Some('!') | Some('^'));
+    let mut i = if negate { 1 } else { 0 };
+    let start = i;
+    let mut found = false;
+    while i < p.len() {
+        // a ] straight after the [ is part of the set
+        if p[i] == ']' && i > start {
+            return Some((found != negate, i + 1));
+        }
+        if i + 2 < p.len() && p[i + 1] == '-' && p[i + 2] != ']' {
+            found |= p[i] <= c && c <= p[i + 2];
+            i += 3;
+        } else {
+            found |= p[i] == c;
+            i += 1;
+        }
+    }
+    None
+}
+
+// glob reports whether name matches pattern, where * matches any characters,
+// ? matches one and [...] matches one in the set
+fn glob(p: &[char], name: &[char]) -> bool {
+    match (p.first(), name.first()) {
+        (None, _) => name.is_empty(),
+        (Some('*'), _) => (0..=name.len()).any(|i| glob(&p[1..], &name[i..])),
+        (_, None) => false,
+        (Some('?'), _) => glob(&p[1..], &name[1..]),
+        (Some('['), Some(&c)) => match class(&p[1..], c) {
+            Some((true, len)) => glob(&p[1 + len..], &name[1..]),
+            Some((false, _)) => false,
+            None => c == '[' && glob(&p[1..], &name[1..]),
+        },
+        (Some('\\'), Some(c)) if p.len() > 1 => p[1] == *c && glob(&p[2..], &name[1..]),
+        (Some(a), Some(b)) => a == b && glob(&p[1..], &name[1..]),
+    }
+}
+
+fn is_glob(pattern: &str) -> bool {
+    pattern.contains(['*', '?', '['])
+}
+
+// wildcard returns the files matching pattern, sorted. A pattern without
+// wildcards names itself if the file exists. Unlike the shell, a pattern
+// that matches nothing is dropped rather than kept as it was written. Files
+// starting with a dot are only matched by a pattern that does too.
+pub fn wildcard(pattern: &str) -> Vec<String> {
+    if !is_glob(pattern) {
+        if Path::new(pattern).exists() {
+            return vec![pattern.to_string()];
+        }
+        return vec![];
+    }
+    let (mut paths, rest) = match pattern.strip_prefix('/') {
+        Some(rest) => (vec!["/".to_string()], rest),
+        None => (vec![String::new()], pattern),
+    };
+    let components: Vec<&str> = rest.split('/').collect();
+    for (n, component) in components.iter().enumerate() {
+        let last = n == components.len() - 1;
+        let mut next = Vec::new();
+        for prefix in &paths {
+            let path = |name: &str| match prefix.as_str() {
+                "" => name.to_string(),
+                p if p.ends_with('/') => format!("{}{}", p, name),
+                p => format!("{}/{}", p, name),
+            };
+            if !is_glob(component) {
+                // an empty component comes from a trailing or doubled slash
+                let p = path(component);
+                if Path::new(&p).exists() {
+                    next.push(p);
+                }
+                continue;
+            }
+            let dir = if prefix.is_empty() { "." } else { prefix };
+            let entries = match fs::read_dir(dir) {
+                Ok(entries) => entries,
+                Err(_) => continue,
+            };
+            let p: Vec<char> = component.chars().collect();
+            for entry in entries.flatten() {
+                let name = entry.file_name().to_string_lossy().to_string();
+                if name.starts_with('.') && !component.starts_with('.') {
+                    continue;
+                }
+                let is_dir = entry.path().is_dir();
+                if (last || is_dir) && glob(&p, &name.chars().collect::<Vec<_>>()) {
+                    next.push(path(&name));
+                }
+            }
+        }
+        paths = next;
+    }
+    paths.sort();
+    paths
+}
+
+// realpath returns the canonical absolute name of a file, with every
+// symbolic link resolved, or None if it doesn't exist
+pub fn realpath(name: &str) -> Option<String> {
+    fs::canonicalize(name).ok().map(|p| p.display().to_string())
+}
+
+// abspath returns the absolute name of a file, removing . and .. but without
+// resolving symbolic links. The file need not exist.
+pub fn abspath(name: &str) -> String {
+    let path = Path::new(name);
+    let path = if path.is_absolute() {
+        path.to_path_buf()
+    } else {
+        env::current_dir().unwrap_or_default().join(path)
+    };
+    let mut res = PathBuf::new();
+    for c in path.components() {
+        match c {
+            Component::ParentDir => {
+                res.pop();
+            }
+            Component::CurDir => {}
+            c => res.push(c),
+        }
+    }
+    res.display().to_string()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn call(function: &str, args: &[&str]) -> Result<String, String> {
+        let args: Vec<String> = args.iter().map(|a| a.to_string()).collect();
+        super::call(function, &args)
+    }
+
+    #[test]
+    fn test_subst() {
+        assert_eq!(
+            subst("ee", "EE", "feet on the street"),
+            "fEEt on the strEEt"
+        );
+        assert_eq!(subst("", "x", "a b"), "a bx");
+        assert_eq!(subst(".c", " .o", "a.c"), "a .o");
+    }
+
+    #[test]
+    fn test_patsubst() {
+        assert_eq!(
+            patsubst("%.c", "%.o", "x.c.c  bar.c baz.h"),
+            "x.c.o bar.o baz.h"
+        );
+        assert_eq!(patsubst("main.c", "%.o", "main.c util.c"), "%.o util.c");
+        assert_eq!(patsubst("src/%.c", "obj/%.o", "src/a.c b.c"), "obj/a.o b.c");
+        assert_eq!(substitution("foo.c bar.c", ".c", ".o"), "foo.o bar.o");
+        assert_eq!(substitution("a.c.c c", ".c", ".o"), "a.c.o c");
+        assert_eq!(substitution("foo.c bar.h", "%.c", "%.o"), "foo.o bar.h");
+    }
+
+    #[test]
+    fn test_words() {
+        assert_eq!(call("strip", &["  a   b\tc  "]), Ok("a b c".to_string()));
+        assert_eq!(findstring("a", "a b c"), "a");
+        assert_eq!(findstring("x", "a b c"), "");
+        assert_eq!(
+            filter("%.c %.s", "foo.c bar.c baz.s ugh.h", true),
+            "foo.c bar.c baz.s"
+        );
+        assert_eq!(filter("%.c main.o", "foo.c main.o a.o", false), "a.o");
+        assert_eq!(sort("foo bar lose foo"), "bar foo lose");
+        assert_eq!(call("words", &["foo bar baz"]), Ok("3".to_string()));
+        assert_eq!(call("words", &[""]), Ok("0".to_string()));
+        assert_eq!(call("firstword", &["foo bar"]), Ok("foo".to_string()));
+        assert_eq!(call("lastword", &["foo bar"]), Ok("bar".to_string()));
+        assert_eq!(call("lastword", &[" "]), Ok("".to_string()));
+    }
+
+    #[test]
+    fn test_word() {
+        assert_eq!(word("2", "foo bar baz"), Ok("bar"));
+        assert_eq!(word(" 4", "foo bar baz"), Ok(""));
+        assert_eq!(
+            word("0", "foo"),
+            Err("first argument to 'word' function must be greater than 0".to_string())
+        );
+        assert_eq!(
+            word("x", "foo"),
+            Err("non-numeric first argument to 'word' function: 'x'".to_string())
+        );
+        assert_eq!(wordlist("2", "3", "foo bar baz"), Ok("bar baz".to_string()));
+        assert_eq!(wordlist("2", "9", "foo bar baz"), Ok("bar baz".to_string()));
+        assert_eq!(wordlist("3", "1", "foo bar baz"), Ok("".to_string()));
+        assert_eq!(wordlist("1", "0", "foo"), Ok("".to_string()));
+        assert_eq!(
+            wordlist("2", "18446744073709551615", "foo bar baz"),
+            Ok("bar baz".to_string())
+        );
+        assert!(wordlist("0", "1", "foo").is_err());
+        assert!(wordlist("1", "-1", "foo").is_err());
+    }
+
+    #[test]
+    fn test_file_names() {
+        assert_eq!(
+            call("dir", &["src/foo.c hacks /a/b/"]),
+            Ok("src/ ./ /a/b/".to_string())
+        );
+        assert_eq!(
+            call("notdir", &["src/foo.c hacks a/"]),
+            Ok("foo.c hacks ".to_string())
+        );
+        assert_eq!(
+            call("suffix", &["src/foo.c src-1.0/bar.c hacks .x"]),
+            Ok(".c .c .x".to_string())
+        );
+        assert_eq!(
+            call("basename", &["src/foo.c src-1.0/bar hacks"]),
+            Ok("src/foo src-1.0/bar hacks".to_string())
+        );
+        assert_eq!(
+            call("addsuffix", &[".c", "foo bar"]),
+            Ok("foo.c bar.c".to_string())
+        );
+        assert_eq!(
+            call("addprefix", &["src/", "foo bar"]),
+            Ok("src/foo src/bar".to_string())
+        );
+        assert_eq!(join("a b c", ".c .o"), "a.c b.o c");
+        assert_eq!(join("a", ".c .o"), "a.c .o");
+    }
+
+    #[test]
+    fn test_glob() {
+        let glob = |p: &str, name: &str| {
+            super::glob(
+                &p.chars().collect::<Vec<_>>(),
+                &name.chars().collect::<Vec<_>>(),
+            )
+        };
+        assert!(glob("*.c", "foo.c"));
+        assert!(glob("*", ""));
+        assert!(!glob("*.c", "foo.h"));
+        assert!(glob("f?o.[ch]", "foo.h"));
+        assert!(glob("[a-c]*", "bar"));
+        assert!(!glob("[!a-c]*", "bar"));
+        assert!(glob("[]]", "]"));
+        assert!(glob("a[b", "a[b"));
+        assert!(glob("\\*", "*"));
+        assert!(!glob("\\*", "x"));
+    }
+
+    #[test]
+    fn test_wildcard() {
+        assert_eq!(
+            wildcard("assets/0*.mk"),
+            vec![
+                "assets/01-simple.mk",
+                "assets/02-complex.mk",
+                "assets/03-conditionals.mk"
+            ]
+        );
+        assert_eq!(
+            wildcard("ass?ts/*/c[xy]*-[a]*"),
+            vec!["assets/includes/cycle-a.mk"]
+        );
+        assert_eq!(wildcard("assets/*/"), vec!["assets/includes/"]);
+        assert_eq!(wildcard("assets/includes"), vec!["assets/includes"]);
+        assert!(wildcard("assets/*.missing").is_empty());
+        assert!(wildcard("missing.mk").is_empty());
+        assert_eq!(
+            call("wildcard", &["missing/* assets/01-*.mk Cargo.toml"]),
+            Ok("assets/01-simple.mk Cargo.toml".to_string())
+        );
+    }
+
+    #[test]
+    fn test_paths() {
+        let cwd = env::current_dir().unwrap();
+        assert_eq!(
+            abspath("./src/../assets//x.mk"),
+            cwd.join("assets/x.mk").display().to_string()
+        );
+        assert_eq!(abspath("/a/./b/../c"), "/a/c");
+        assert_eq!(
+            realpath("src/../Cargo.toml"),
+            Some(
+                cwd.join("Cargo.toml")
+                    .canonicalize()
+                    .unwrap()
+                    .display()
+                    .to_string()
+            )
+        );
+        assert_eq!(realpath("missing.mk"), None);
+        assert_eq!(call("realpath", &["missing.mk"]), Ok("".to_string()));
+    }
+
+    #[test]
+    fn test_call() {
+        assert_eq!(
+            call("subst", &["a", "b"]),
+            Err("insufficient number of arguments (2) to function 'subst'".to_string())
+        );
+        assert_eq!(
+            call("patsubst", &["%.c", "%.o", "a.c"]),
+            Ok("a.o".to_string())
+        );
+        assert_eq!(
+            call("shell", &["echo hi"]),
+            Err("function 'shell' is not supported".to_string())
+        );
+    }
+}
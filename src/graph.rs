@@ -1,69 +1,961 @@
-use daggy::{Dag, NodeIndex};
-use std::collections::HashMap;
+use daggy::{Dag, EdgeIndex, NodeIndex, Walker};
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::fs;
+use std::path::Path;
+use std::time::SystemTime;
 
-use crate::types::Makefile;
+use crate::pattern::{self, Match};
+use crate::types::{Makefile, Prerequisite, RecipeLine, Rule, RuleKind};
+use crate::vpath::Search;
 
-pub fn from_makefile<'a>(makefile: Makefile) -> Result<Dag<&str, u32, u32>, &'a str> {
-    let mut res = Dag::new();
-    let mut nodes: HashMap<&str, NodeIndex> = HashMap::new();
+// A Node is a file or phony target along with the recipe used to make it.
+// Targets of double-colon rules have a recipe for each rule instead.
+// Grouped targets share one node, named after the first of them, with every
+// target of the group listed in group.
+#[derive(Debug, PartialEq, Clone)]
+pub struct Node<'a> {
+    pub name: String,
+    pub group: Vec<String>,
+    pub recipe: Vec<RecipeLine<'a>>,
+    // the edges to the prerequisites of the rule that gave the recipe, which
+    // come before those of any other rules in $< and $^
+    pub recipe_edges: Vec<EdgeIndex>,
+    pub double_colon: Vec<DoubleColon<'a>>,
+    // the stem matched when the recipe came from a pattern rule
+    pub stem: Option<String>,
+    pub phony: bool,
+    // where the file was found by directory search, if it isn't at name
+    pub path: Option<String>,
+}
+
+impl<'a> Node<'a> {
+    fn new(name: &str) -> Node<'a> {
+        Node {
+            name: name.to_string(),
+            group: Vec::new(),
+            recipe: Vec::new(),
+            recipe_edges: Vec::new(),
+            double_colon: Vec::new(),
+            stem: None,
+            phony: false,
+            path: None,
+        }
+    }
+
+    // file returns the name of the file the node refers to
+    pub fn file(&self) -> &str {
+        self.path.as_deref().unwrap_or(&self.name)
+    }
+
+    // files returns the names of the files made by the node's recipe
+    pub fn files(&self) -> Vec<&str> {
+        if self.group.is_empty() {
+            vec![self.file()]
+        } else {
+            self.group.iter().map(String::as_str).collect()
+        }
+    }
+
+    // has_recipe reports whether any rule gave the node a recipe
+    fn has_recipe(&self) -> bool {
+        !self.recipe.is_empty() || !self.double_colon.is_empty()
+    }
+}
+
+// A DoubleColon is one of the double-colon rules for a target. Its recipe
+// is run when the target is older than any of the prerequisites of that
+// rule, independently of the other rules for the target, as checked by
+// is_double_colon_out_of_date.
+#[derive(Debug, PartialEq, Clone)]
+pub struct DoubleColon<'a> {
+    pub prerequisites: Vec<String>,
+    pub recipe: Vec<RecipeLine<'a>>,
+}
+
+impl fmt::Display for Node<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.group.is_empty() {
+            write!(f, "{}", self.name)
+        } else {
+            write!(f, "{}", self.group.join(" "))
+        }
+    }
+}
+
+// An Edge connects a target to one of its prerequisites. Order-only
+// prerequisites must exist before the target is made but being newer than
+// the target doesn't make it out of date.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Edge {
+    Normal,
+    OrderOnly,
+}
+
+impl fmt::Display for Edge {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Edge::Normal => write!(f, "normal"),
+            Edge::OrderOnly => write!(f, "order-only"),
+        }
+    }
+}
+
+pub type Graph<'a> = Dag<Node<'a>, Edge, u32>;
+
+// from_makefile builds the graph for a Makefile, using search to find
+// prerequisites that aren't in the current directory
+pub fn from_makefile<'a>(makefile: Makefile<'a>, search: &Search) -> Result<Graph<'a>, String> {
+    let exists = |name: &str| Path::new(name).exists();
+    let mut dag = build(makefile, |name| {
+        exists(name) || search.find(name, exists).is_some()
+    })?;
+    resolve(&mut dag, search, exists);
+    Ok(dag)
+}
+
+// resolve sets the path of every file that doesn't exist where it is named
+// but can be found by directory search
+fn resolve<F: Fn(&str) -> bool>(dag: &mut Graph, search: &Search, exists: F) {
+    for n in 0..dag.node_count() {
+        let node = &mut dag[NodeIndex::new(n)];
+        if !node.phony && !exists(&node.name) {
+            node.path = search.find(&node.name, &exists);
+        }
+    }
+}
+
+// automatic_variables returns the values of $@, $<, $^, $| and $* for the
+// recipe of target. Prerequisites are named by the path they were found at.
+pub fn automatic_variables(dag: &Graph, target: NodeIndex) -> Vec<(String, String)> {
+    let node = &dag[target];
+    let mut children: Vec<_> = dag.children(target).iter(dag).collect();
+    children.sort_by_key(|(e, _)| {
+        let first = node.recipe_edges.iter().position(|r| r == e);
+        (first.unwrap_or(usize::MAX), *e)
+    });
+    let files = |edge: Edge| -> Vec<&str> {
+        children
+            .iter()
+            .filter(|(e, _)| dag[*e] == edge)
+            .map(|(_, n)| dag[*n].file())
+            .collect()
+    };
+    let normal = files(Edge::Normal);
+    vec![
+        ("@".to_string(), node.name.clone()),
+        ("<".to_string(), normal.first().unwrap_or(&"").to_string()),
+        ("^".to_string(), normal.join(" ")),
+        ("|".to_string(), files(Edge::OrderOnly).join(" ")),
+        ("*".to_string(), node.stem.clone().unwrap_or_default()),
+    ]
+}
+
+// modified returns the modification time of a file, or None if it doesn't exist
+pub fn modified(name: &str) -> Option<SystemTime> {
+    fs::metadata(name).and_then(|m| m.modified()).ok()
+}
+
+// is_out_of_date reports whether target needs to be made, using mtime to
+// find when a file was last modified. A target is out of date if it is phony
+// or doesn't exist, or if any of its normal prerequisites are out of date or
+// newer than it. Order-only prerequisites are ignored. Grouped targets are
+// out of date if any one of them is, and targets of double-colon rules if
+// the recipe of any one of the rules needs to be run.
+pub fn is_out_of_date<F: Fn(&str) -> Option<SystemTime>>(
+    dag: &Graph,
+    target: NodeIndex,
+    mtime: &F,
+) -> bool {
+    out_of_date(dag, target, mtime, &mut HashMap::new())
+}
+
+// is_double_colon_out_of_date reports whether the recipe of rule, one of the
+// double-colon rules for target, needs to be run. Only the prerequisites of
+// that rule are checked, and a rule without any is always run.
+pub fn is_double_colon_out_of_date<F: Fn(&str) -> Option<SystemTime>>(
+    dag: &Graph,
+    target: NodeIndex,
+    rule: &DoubleColon,
+    mtime: &F,
+) -> bool {
+    let node = &dag[target];
+    match times(node, mtime) {
+        _ if node.phony || rule.prerequisites.is_empty() => true,
+        None => true,
+        Some((oldest, _)) => {
+            let prerequisites = dag
+                .children(target)
+                .iter(dag)
+                .map(|(_, n)| n)
+                .filter(|n| rule.prerequisites.iter().any(|p| p == &dag[*n].name))
+                .collect();
+            newer(dag, prerequisites, oldest, mtime, &mut HashMap::new())
+        }
+    }
+}
 
-    for i in makefile.rules {
-        for t in i.targets {
-            if t == ".PHONY" {
-                continue;
+// times returns the oldest and newest modification times of the files made
+// by a node, or None if any of them doesn't exist
+fn times<F: Fn(&str) -> Option<SystemTime>>(
+    node: &Node,
+    mtime: &F,
+) -> Option<(SystemTime, SystemTime)> {
+    let times = node
+        .files()
+        .into_iter()
+        .map(mtime)
+        .collect::<Option<Vec<_>>>()?;
+    Some((*times.iter().min()?, *times.iter().max()?))
+}
+
+fn out_of_date<F: Fn(&str) -> Option<SystemTime>>(
+    dag: &Graph,
+    target: NodeIndex,
+    mtime: &F,
+    seen: &mut HashMap<NodeIndex, bool>,
+) -> bool {
+    if let Some(res) = seen.get(&target) {
+        return *res;
+    }
+    let node = &dag[target];
+    let res = match times(node, mtime) {
+        _ if node.phony => true,
+        None => true,
+        // a double-colon rule without prerequisites is always run
+        Some(_) if node.double_colon.iter().any(|d| d.prerequisites.is_empty()) => true,
+        Some((oldest, _)) => {
+            let prerequisites = dag
+                .children(target)
+                .iter(dag)
+                .filter(|(e, _)| dag[*e] == Edge::Normal)
+                .map(|(_, n)| n)
+                .collect();
+            newer(dag, prerequisites, oldest, mtime, seen)
+        }
+    };
+    seen.insert(target, res);
+    res
+}
+
+// newer reports whether any of prerequisites is out of date or was modified
+// after time
+fn newer<F: Fn(&str) -> Option<SystemTime>>(
+    dag: &Graph,
+    prerequisites: Vec<NodeIndex>,
+    time: SystemTime,
+    mtime: &F,
+    seen: &mut HashMap<NodeIndex, bool>,
+) -> bool {
+    prerequisites.into_iter().any(|p| {
+        out_of_date(dag, p, mtime, seen)
+            || times(&dag[p], mtime).is_some_and(|(_, newest)| newest > time)
+    })
+}
+
+// node returns the index of the node called name, adding it if needed
+fn node<'a>(dag: &mut Graph<'a>, nodes: &mut HashMap<String, NodeIndex>, name: &str) -> NodeIndex {
+    match nodes.get(name) {
+        Some(n) => *n,
+        None => {
+            let n = dag.add_node(Node::new(name));
+            nodes.insert(name.to_string(), n);
+            n
+        }
+    }
+}
+
+// add_edge connects a target to a prerequisite. A normal edge takes the
+// place of an order-only edge between the same nodes.
+fn add_edge(
+    dag: &mut Graph,
+    target: NodeIndex,
+    prerequisite: NodeIndex,
+    edge: Edge,
+) -> Result<EdgeIndex, String> {
+    match dag.find_edge(target, prerequisite) {
+        Some(e) => {
+            if edge == Edge::Normal {
+                dag[e] = Edge::Normal;
             }
+            Ok(e)
+        }
+        None => dag
+            .add_edge(target, prerequisite, edge)
+            .map_err(|_| "Graph would cycle".to_string()),
+    }
+}
+
+// add_rule adds the target of a rule with its prerequisites and recipe.
+// Double-colon rules add their recipe alongside any others for the target.
+#[allow(clippy::too_many_arguments)]
+fn add_rule<'a, P: AsRef<str>>(
+    dag: &mut Graph<'a>,
+    nodes: &mut HashMap<String, NodeIndex>,
+    colons: &mut HashMap<String, bool>,
+    target: &str,
+    double_colon: bool,
+    prerequisites: &[P],
+    order_only: &[P],
+    recipe: &[RecipeLine<'a>],
+) -> Result<NodeIndex, String> {
+    if *colons.entry(target.to_string()).or_insert(double_colon) != double_colon {
+        return Err(format!(
+            "target file '{}' has both : and :: entries",
+            target
+        ));
+    }
+    let tn = node(dag, nodes, target);
+    let mut edges = Vec::new();
+    for p in prerequisites {
+        let pn = node(dag, nodes, p.as_ref());
+        edges.push(add_edge(dag, tn, pn, Edge::Normal)?);
+    }
+    if double_colon {
+        dag[tn].double_colon.push(DoubleColon {
+            prerequisites: prerequisites
+                .iter()
+                .map(|p| p.as_ref().to_string())
+                .collect(),
+            recipe: recipe.to_vec(),
+        });
+    } else if !recipe.is_empty() {
+        dag[tn].recipe = recipe.to_vec();
+        dag[tn].recipe_edges = edges;
+    }
+    for p in order_only {
+        let pn = node(dag, nodes, p.as_ref());
+        add_edge(dag, tn, pn, Edge::OrderOnly)?;
+    }
+    Ok(tn)
+}
 
-            if !nodes.contains_key(t) {
-                let x = res.add_node(t);
-                nodes.insert(t, x);
+// A PatternRule is a pattern rule from the Makefile, or one converted from
+// a suffix rule
+struct PatternRule<'a> {
+    targets: Vec<String>,
+    prerequisites: Vec<String>,
+    order_only: Vec<String>,
+    recipe: Vec<RecipeLine<'a>>,
+}
+
+impl<'a> From<&Rule<'a>> for PatternRule<'a> {
+    fn from(rule: &Rule<'a>) -> Self {
+        let names = |v: &[Prerequisite]| v.iter().map(|p| p.name.to_string()).collect();
+        PatternRule {
+            targets: rule.targets.iter().map(|t| t.to_string()).collect(),
+            prerequisites: names(&rule.prerequsities),
+            order_only: names(&rule.order_only),
+            recipe: rule.recipe.clone(),
+        }
+    }
+}
+
+// DEFAULT_SUFFIXES is the suffix list before any .SUFFIXES rule, as in GNU make
+const DEFAULT_SUFFIXES: &[&str] = &[
+    ".out", ".a", ".ln", ".o", ".c", ".cc", ".C", ".cpp", ".p", ".f", ".F", ".m", ".r", ".y", ".l",
+    ".ym", ".yl", ".s", ".S", ".mod", ".sym", ".def", ".h", ".info", ".dvi", ".tex", ".texinfo",
+    ".texi", ".txinfo", ".w", ".ch", ".web", ".sh", ".elc", ".el",
+];
+
+// suffixes returns the suffix list after every .SUFFIXES rule has been
+// read. Each one adds its prerequisites, or clears the list if it has none.
+fn suffixes<'a>(makefile: &Makefile<'a>) -> Vec<&'a str> {
+    let mut res = DEFAULT_SUFFIXES.to_vec();
+    for rule in makefile.rules() {
+        if rule.kind == RuleKind::Explicit && rule.targets.contains(&".SUFFIXES") {
+            if rule.prerequsities.is_empty() {
+                res.clear();
             }
+            res.extend(rule.prerequsities.iter().map(|p| p.name));
+        }
+    }
+    res
+}
 
-            for p in &i.prerequsities {
-                if !nodes.contains_key(p) {
-                    let x = res.add_node(p);
-                    nodes.insert(p, x);
-                }
-                let tn = nodes.get(t).unwrap();
-                let pn = nodes.get(p).unwrap();
+// suffix_rule returns the target and prerequisite patterns of the pattern
+// rule equivalent to a suffix rule, if target is one. `.c.o` is the same as
+// `%.o: %.c` and `.c` is the same as `%: %.c`.
+fn suffix_rule(target: &str, suffixes: &[&str]) -> Option<(String, String)> {
+    if suffixes.contains(&target) {
+        return Some(("%".to_string(), format!("%{}", target)));
+    }
+    suffixes.iter().find_map(|s| {
+        target
+            .strip_prefix(s)
+            .filter(|t| suffixes.contains(t))
+            .map(|t| (format!("%{}", t), format!("%{}", s)))
+    })
+}
+
+// find_pattern_rule returns the pattern rule with the shortest stem that
+// matches name and whose prerequisites all exist or ought to exist. A pattern
+// rule without a recipe cancels the rules before it with the same targets
+// and prerequisites.
+fn find_pattern_rule<'r, 'a, F: Fn(&str) -> bool>(
+    patterns: &'r [PatternRule<'a>],
+    name: &str,
+    exists: F,
+) -> Option<(&'r PatternRule<'a>, Match)> {
+    let mut best: Option<(&PatternRule, Match)> = None;
+    for (idx, rule) in patterns.iter().enumerate() {
+        let cancelled = patterns[idx + 1..].iter().any(|r| {
+            r.recipe.is_empty()
+                && r.targets == rule.targets
+                && r.prerequisites == rule.prerequisites
+        });
+        if rule.recipe.is_empty() || cancelled {
+            continue;
+        }
+        for t in &rule.targets {
+            let m = match pattern::match_target(t, name) {
+                Some(m) => m,
+                None => continue,
+            };
+            let shorter = match &best {
+                Some((_, b)) => m.full_stem().len() < b.full_stem().len(),
+                None => true,
+            };
+            if shorter
+                && rule
+                    .prerequisites
+                    .iter()
+                    .chain(&rule.order_only)
+                    .all(|p| exists(&m.substitute(p)))
+            {
+                best = Some((rule, m));
+            }
+        }
+    }
+    best
+}
 
-                if res.add_edge(*tn, *pn, 1).is_err() {
-                    return Err("Graph would cycle");
+// build assembles the graph, using exists to check whether a file is present
+// when deciding if a pattern rule applies
+fn build<F: Fn(&str) -> bool>(makefile: Makefile<'_>, exists: F) -> Result<Graph<'_>, String> {
+    let mut res = Dag::new();
+    let mut nodes: HashMap<String, NodeIndex> = HashMap::new();
+    let mut phony: HashSet<&str> = HashSet::new();
+    let mut colons: HashMap<String, bool> = HashMap::new();
+    let mut patterns: Vec<PatternRule> = Vec::new();
+    let mut suffix_rules: Vec<PatternRule> = Vec::new();
+    let suffixes = suffixes(&makefile);
+
+    // Grouped targets share a single node, so every name in the group refers
+    // to it wherever it appears
+    for i in makefile.rules() {
+        if !i.grouped || i.kind != RuleKind::Explicit {
+            continue;
+        }
+        let n = match i.targets.iter().find_map(|t| nodes.get(*t)) {
+            Some(n) => *n,
+            None => res.add_node(Node::new(i.targets[0])),
+        };
+        for &t in &i.targets {
+            nodes.insert(t.to_string(), n);
+            if !res[n].group.iter().any(|g| g == t) {
+                res[n].group.push(t.to_string());
+            }
+        }
+    }
+
+    for i in makefile.rules() {
+        match i.kind {
+            RuleKind::Pattern => patterns.push(i.into()),
+            RuleKind::StaticPattern(target_pattern) => {
+                for &t in &i.targets {
+                    let stem = match pattern::stem(target_pattern, t) {
+                        Some(stem) => stem,
+                        None => {
+                            return Err(format!(
+                                "{}: target '{}' doesn't match the target pattern '{}'",
+                                i.span, t, target_pattern
+                            ))
+                        }
+                    };
+                    let substitute = |prereqs: &[Prerequisite]| -> Vec<String> {
+                        prereqs
+                            .iter()
+                            .map(|p| pattern::substitute(p.name, stem))
+                            .collect()
+                    };
+                    let tn = add_rule(
+                        &mut res,
+                        &mut nodes,
+                        &mut colons,
+                        t,
+                        i.double_colon,
+                        &substitute(&i.prerequsities),
+                        &substitute(&i.order_only),
+                        &i.recipe,
+                    )?;
+                    res[tn].stem = Some(stem.to_string());
+                }
+            }
+            RuleKind::Explicit => {
+                let targets = if i.grouped {
+                    &i.targets[..1]
+                } else {
+                    &i.targets[..]
+                };
+                for &t in targets {
+                    if t == ".PHONY" {
+                        phony.extend(i.prerequsities.iter().map(|p| p.name));
+                        continue;
+                    }
+                    if t == ".SUFFIXES" {
+                        continue;
+                    }
+                    // a suffix rule with prerequisites is an ordinary rule, and
+                    // one without a recipe does nothing rather than cancelling
+                    // the pattern rule it is equivalent to
+                    if i.prerequsities.is_empty() && !i.recipe.is_empty() {
+                        if let Some((target, prerequisite)) = suffix_rule(t, &suffixes) {
+                            suffix_rules.push(PatternRule {
+                                targets: vec![target],
+                                prerequisites: vec![prerequisite],
+                                order_only: Vec::new(),
+                                recipe: i.recipe.clone(),
+                            });
+                            continue;
+                        }
+                    }
+                    add_rule(
+                        &mut res,
+                        &mut nodes,
+                        &mut colons,
+                        t,
+                        i.double_colon,
+                        &i.prerequsities,
+                        &i.order_only,
+                        &i.recipe,
+                    )?;
                 }
             }
         }
     }
+
+    // Suffix rules come after the pattern rules written as such
+    patterns.extend(suffix_rules);
+
+    // Search the pattern rules for every target that has no recipe. Files
+    // added as prerequisites by a pattern rule are searched in turn.
+    let mut pending: Vec<NodeIndex> = (0..res.node_count()).rev().map(NodeIndex::new).collect();
+    while let Some(n) = pending.pop() {
+        if res[n].has_recipe() || phony.contains(res[n].name.as_str()) {
+            continue;
+        }
+        let found = find_pattern_rule(&patterns, &res[n].name, |p| {
+            nodes.contains_key(p) || exists(p)
+        });
+        let (rule, m) = match found {
+            Some(found) => found,
+            None => continue,
+        };
+        res[n].recipe = rule.recipe.clone();
+        res[n].stem = Some(m.full_stem());
+        let prerequisites = rule.prerequisites.iter().map(|p| (p, Edge::Normal));
+        let order_only = rule.order_only.iter().map(|p| (p, Edge::OrderOnly));
+        for (p, edge) in prerequisites.chain(order_only) {
+            let p = m.substitute(p);
+            if !nodes.contains_key(&p) {
+                pending.push(node(&mut res, &mut nodes, &p));
+            }
+            let e = add_edge(&mut res, n, nodes[&p], edge)?;
+            if edge == Edge::Normal {
+                res[n].recipe_edges.push(e);
+            }
+        }
+    }
+
+    for p in phony {
+        if let Some(n) = nodes.get(p) {
+            res[*n].phony = true;
+        }
+    }
     Ok(res)
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
-    use crate::types::{Makefile, Rule};
+    use crate::types::{Makefile, Rule, Span, Statement};
+
+    fn prerequisites<'a>(names: &[&'a str]) -> Vec<Prerequisite<'a>> {
+        names
+            .iter()
+            .map(|&name| Prerequisite {
+                name,
+                span: Span::default(),
+            })
+            .collect()
+    }
 
     #[test]
     fn test_from_makefile() {
-        let d = from_makefile(Makefile {
-            variables: Vec::new(),
-            rules: vec![
-                Rule {
-                    targets: vec!["foo", "bar"],
-                    prerequsities: vec!["baz", "quux"],
-                    recipe: Vec::new(),
-                },
-                Rule {
-                    targets: vec![".PHONY"],
-                    prerequsities: vec!["all"],
-                    recipe: Vec::new(),
-                },
-                Rule {
-                    targets: vec!["baz"],
-                    prerequsities: vec!["foobar"],
-                    recipe: Vec::new(),
-                },
-            ],
-        })
+        let d = from_makefile(
+            Makefile {
+                statements: vec![
+                    Statement::Rule(Rule {
+                        kind: RuleKind::Explicit,
+                        double_colon: false,
+                        grouped: false,
+                        targets: vec!["foo", "bar"],
+                        prerequsities: prerequisites(&["baz", "quux"]),
+                        order_only: Vec::new(),
+                        recipe: Vec::new(),
+                        span: Span::default(),
+                    }),
+                    Statement::Rule(Rule {
+                        kind: RuleKind::Explicit,
+                        double_colon: false,
+                        grouped: false,
+                        targets: vec![".PHONY"],
+                        prerequsities: prerequisites(&["all"]),
+                        order_only: Vec::new(),
+                        recipe: Vec::new(),
+                        span: Span::default(),
+                    }),
+                    Statement::Rule(Rule {
+                        kind: RuleKind::Explicit,
+                        double_colon: false,
+                        grouped: false,
+                        targets: vec!["baz"],
+                        prerequsities: prerequisites(&["foobar"]),
+                        order_only: Vec::new(),
+                        recipe: Vec::new(),
+                        span: Span::default(),
+                    }),
+                ],
+            },
+            &Search::default(),
+        )
         .unwrap();
         assert_eq!(d.node_count(), 5);
         assert_eq!(d.edge_count(), 5);
     }
+
+    fn index(d: &Graph, name: &str) -> NodeIndex {
+        NodeIndex::new(
+            d.raw_nodes()
+                .iter()
+                .position(|n| n.weight.name == name || n.weight.group.iter().any(|g| g == name))
+                .unwrap(),
+        )
+    }
+
+    fn commands<'a>(recipe: &[RecipeLine<'a>]) -> Vec<&'a str> {
+        recipe.iter().map(|l| l.command).collect()
+    }
+
+    fn find<'d, 'a>(d: &'d Graph<'a>, name: &str) -> &'d Node<'a> {
+        &d[index(d, name)]
+    }
+
+    #[test]
+    fn test_pattern_rules() {
+        let data = "prog: main.o src/util.o\n\tcc -o $@ $^\n%.o: %.c defs.h\n\tcc -c $<\n";
+        let (_, m) = crate::parser::parse_makefile(data).unwrap();
+        let existing = ["main.c", "src/util.c", "defs.h"];
+        let d = build(m, |p| existing.contains(&p)).unwrap();
+        assert_eq!(d.node_count(), 6);
+        assert_eq!(d.edge_count(), 6);
+        let util = find(&d, "src/util.o");
+        assert_eq!(commands(&util.recipe), vec!["cc -c $<"]);
+        assert_eq!(util.stem, Some("src/util".to_string()));
+        assert_eq!(find(&d, "src/util.c").recipe.len(), 0);
+    }
+
+    #[test]
+    fn test_pattern_rules_need_prerequisites() {
+        let data = "all: foo.o bar.o\n%.o: %.c\n\tcc -c $<\n%.o: %.s\n\tas $<\n";
+        let (_, m) = crate::parser::parse_makefile(data).unwrap();
+        let existing = ["bar.s"];
+        let d = build(m, |p| existing.contains(&p)).unwrap();
+        assert_eq!(find(&d, "foo.o").recipe.len(), 0);
+        assert_eq!(commands(&find(&d, "bar.o").recipe), vec!["as $<"]);
+        assert_eq!(d.node_count(), 4);
+    }
+
+    #[test]
+    fn test_pattern_rules_cancelled() {
+        let data = "all: foo.o bar.o\n%.o: %.c\n\tcc -c $<\n%.o: %.s\n\tas $<\n%.o: %.c\n";
+        let (_, m) = crate::parser::parse_makefile(data).unwrap();
+        let existing = ["foo.c", "bar.c", "bar.s"];
+        let d = build(m, |p| existing.contains(&p)).unwrap();
+        assert_eq!(find(&d, "foo.o").recipe.len(), 0);
+        assert_eq!(commands(&find(&d, "bar.o").recipe), vec!["as $<"]);
+
+        // a rule after the one that cancels it is used
+        let data = "all: foo.o\n%.o: %.c\n\tcc -c $<\n%.o: %.c\n%.o: %.c\n\tgcc -c $<\n";
+        let (_, m) = crate::parser::parse_makefile(data).unwrap();
+        let d = build(m, |p| p == "foo.c").unwrap();
+        assert_eq!(commands(&find(&d, "foo.o").recipe), vec!["gcc -c $<"]);
+    }
+
+    #[test]
+    fn test_pattern_rules_shortest_stem() {
+        let data = "all: lib/foo.o\n%.o: %.c\n\tgeneric\nlib/%.o: lib/%.c\n\tspecific\n";
+        let (_, m) = crate::parser::parse_makefile(data).unwrap();
+        let d = build(m, |p| p == "lib/foo.c").unwrap();
+        assert_eq!(commands(&find(&d, "lib/foo.o").recipe), vec!["specific"]);
+    }
+
+    #[test]
+    fn test_static_pattern_rules() {
+        let data = "all: foo.o bar.o\nfoo.o bar.o: %.o: %.c defs.h\n\tcc -c $<\n";
+        let (_, m) = crate::parser::parse_makefile(data).unwrap();
+        let d = build(m, |_| false).unwrap();
+        assert_eq!(d.node_count(), 6);
+        assert_eq!(d.edge_count(), 6);
+        let bar = find(&d, "bar.o");
+        assert_eq!(commands(&bar.recipe), vec!["cc -c $<"]);
+        assert_eq!(bar.stem, Some("bar".to_string()));
+        find(&d, "bar.c");
+    }
+
+    #[test]
+    fn test_static_pattern_rules_mismatch() {
+        let data = "foo.o foo.c: %.o: %.c\n\tcc -c $<\n";
+        let (_, m) = crate::parser::parse_makefile(data).unwrap();
+        assert_eq!(
+            build(m, |_| false).err(),
+            Some("1:1: target 'foo.c' doesn't match the target pattern '%.o'".to_string())
+        );
+    }
+
+    #[test]
+    fn test_order_only_edges() {
+        let data = "obj/a.o: a.c | obj\nobj/b.o: b.c | obj b.c\nobj/%.o: %.h | obj\n\tcc\n";
+        let (_, m) = crate::parser::parse_makefile(data).unwrap();
+        let d = build(m, |p| p.ends_with(".h")).unwrap();
+        let edge = |a, b| d[d.find_edge(index(&d, a), index(&d, b)).unwrap()];
+        assert_eq!(edge("obj/a.o", "a.c"), Edge::Normal);
+        assert_eq!(edge("obj/a.o", "obj"), Edge::OrderOnly);
+        assert_eq!(edge("obj/a.o", "a.h"), Edge::Normal);
+        assert_eq!(edge("obj/b.o", "b.c"), Edge::Normal);
+        assert_eq!(d.edge_count(), 6);
+    }
+
+    #[test]
+    fn test_is_out_of_date() {
+        let data = ".PHONY: clean\nclean:\nprog: main.o | bin\nmain.o: main.c\nbin:\n";
+        let (_, m) = crate::parser::parse_makefile(data).unwrap();
+        let d = build(m, |_| false).unwrap();
+        let time = |s| Some(SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(s));
+
+        // bin is newer than everything but is only an order-only prerequisite
+        let mtime = |name: &str| match name {
+            "main.c" => time(1),
+            "main.o" => time(2),
+            "prog" => time(3),
+            "bin" => time(4),
+            _ => None,
+        };
+        assert!(!is_out_of_date(&d, index(&d, "prog"), &mtime));
+        assert!(!is_out_of_date(&d, index(&d, "main.o"), &mtime));
+        assert!(is_out_of_date(&d, index(&d, "clean"), &mtime));
+
+        let mtime = |name: &str| match name {
+            "main.c" => time(5),
+            "main.o" => time(2),
+            "prog" => time(3),
+            _ => None,
+        };
+        assert!(is_out_of_date(&d, index(&d, "main.o"), &mtime));
+        assert!(is_out_of_date(&d, index(&d, "prog"), &mtime));
+
+        let mtime = |name: &str| match name {
+            "main.c" => time(1),
+            "main.o" => time(2),
+            _ => None,
+        };
+        assert!(is_out_of_date(&d, index(&d, "prog"), &mtime));
+    }
+
+    #[test]
+    fn test_double_colon_rules() {
+        let data = "clean:: a\n\trm a\nclean:: b\n\trm b\nclean::\n\trm c\n";
+        let (_, m) = crate::parser::parse_makefile(data).unwrap();
+        let d = build(m, |_| false).unwrap();
+        assert_eq!(d.node_count(), 3);
+        let clean = find(&d, "clean");
+        assert!(clean.recipe.is_empty());
+        let rules: Vec<_> = clean
+            .double_colon
+            .iter()
+            .map(|r| (r.prerequisites.clone(), commands(&r.recipe)))
+            .collect();
+        assert_eq!(
+            rules,
+            vec![
+                (vec!["a".to_string()], vec!["rm a"]),
+                (vec!["b".to_string()], vec!["rm b"]),
+                (Vec::new(), vec!["rm c"]),
+            ]
+        );
+
+        // each rule only runs when its own prerequisites are newer
+        let time = |s| Some(SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(s));
+        let mtime = |name: &str| match name {
+            "a" => time(1),
+            "clean" => time(2),
+            "b" => time(3),
+            _ => None,
+        };
+        let n = index(&d, "clean");
+        let run: Vec<bool> = clean
+            .double_colon
+            .iter()
+            .map(|r| is_double_colon_out_of_date(&d, n, r, &mtime))
+            .collect();
+        assert_eq!(run, vec![false, true, true]);
+        assert!(is_out_of_date(&d, n, &mtime));
+
+        let (_, m) = crate::parser::parse_makefile("all: a\nall:: b\n").unwrap();
+        assert_eq!(
+            build(m, |_| false).err(),
+            Some("target file 'all' has both : and :: entries".to_string())
+        );
+    }
+
+    #[test]
+    fn test_automatic_variables_recipe_first() {
+        // the prerequisites of the rule with the recipe come first
+        let data = "foo.o: foo.h\n%.o: %.c\n\tcc -c $<\nbar.o: bar.h\nbar.o: bar.c\n\tcc -c $<\n";
+        let (_, m) = crate::parser::parse_makefile(data).unwrap();
+        let d = build(m, |p| p == "foo.c").unwrap();
+        for (target, first, all) in &[
+            ("foo.o", "foo.c", "foo.c foo.h"),
+            ("bar.o", "bar.c", "bar.c bar.h"),
+        ] {
+            let vars = automatic_variables(&d, index(&d, target));
+            assert_eq!(vars[1], ("<".to_string(), first.to_string()));
+            assert_eq!(vars[2], ("^".to_string(), all.to_string()));
+        }
+    }
+
+    #[test]
+    fn test_vpath() {
+        let data = "prog: main.o defs.h | obj\n%.o: %.c defs.h\n\tcc -c $<\n";
+        let (_, m) = crate::parser::parse_makefile(data).unwrap();
+        let mut search = Search::default();
+        search.add("%.h", vec!["include".to_string()]);
+        search.general(vec!["src".to_string()]);
+        let files = ["include/defs.h", "src/main.c", "src/defs.h"];
+        let exists = |p: &str| files.contains(&p);
+        let mut d = build(m, |p| exists(p) || search.find(p, exists).is_some()).unwrap();
+        resolve(&mut d, &search, exists);
+
+        assert_eq!(find(&d, "defs.h").file(), "include/defs.h");
+        assert_eq!(find(&d, "main.c").file(), "src/main.c");
+        assert_eq!(find(&d, "main.o").path, None);
+        assert_eq!(
+            automatic_variables(&d, index(&d, "main.o")),
+            vec![
+                ("@".to_string(), "main.o".to_string()),
+                ("<".to_string(), "src/main.c".to_string()),
+                ("^".to_string(), "src/main.c include/defs.h".to_string()),
+                ("|".to_string(), "".to_string()),
+                ("*".to_string(), "main".to_string()),
+            ]
+        );
+        let prog = automatic_variables(&d, index(&d, "prog"));
+        assert_eq!(prog[2].1, "main.o include/defs.h");
+        assert_eq!(prog[3].1, "obj");
+
+        // timestamps are taken from the path that was found
+        let time = |s| Some(SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(s));
+        let mtime = |name: &str| match name {
+            "src/main.c" => time(1),
+            "include/defs.h" => time(1),
+            "defs.h" => time(5),
+            "main.o" => time(2),
+            "prog" => time(3),
+            _ => None,
+        };
+        assert!(!is_out_of_date(&d, index(&d, "prog"), &mtime));
+    }
+
+    #[test]
+    fn test_suffix_rules() {
+        let data = ".SUFFIXES: .x\nall: main.o tool gen.c\n.c.o:\n\tcc -c $<\n.c:\n\tcc -o $@ $<\n.x.c:\n\tgen $<\n.h.o: foo\n";
+        let (_, m) = crate::parser::parse_makefile(data).unwrap();
+        let d = build(m, |p| ["main.c", "tool.c", "gen.x"].contains(&p)).unwrap();
+        assert_eq!(commands(&find(&d, "main.o").recipe), vec!["cc -c $<"]);
+        assert_eq!(commands(&find(&d, "gen.c").recipe), vec!["gen $<"]);
+        assert!(find(&d, "main.c").recipe.is_empty());
+        assert_eq!(commands(&find(&d, "tool").recipe), vec!["cc -o $@ $<"]);
+        assert_eq!(find(&d, "tool").stem, Some("tool".to_string()));
+        // suffix rules with prerequisites are ordinary targets
+        assert!(d.raw_nodes().iter().any(|n| n.weight.name == ".h.o"));
+        assert!(!d.raw_nodes().iter().any(|n| n.weight.name == ".c.o"));
+        assert!(!d.raw_nodes().iter().any(|n| n.weight.name == ".SUFFIXES"));
+
+        // an empty .SUFFIXES clears the list, so .c.o is an ordinary target
+        let data = ".SUFFIXES:\nmain.o:\n.c.o:\n\tcc -c $<\n.SUFFIXES: .y\n";
+        let (_, m) = crate::parser::parse_makefile(data).unwrap();
+        let d = build(m, |_| true).unwrap();
+        assert!(find(&d, "main.o").recipe.is_empty());
+        assert_eq!(commands(&find(&d, ".c.o").recipe), vec!["cc -c $<"]);
+
+        // a suffix rule without a recipe doesn't cancel the pattern rule
+        let data = "all: foo.o\nfoo.c:\n%.o: %.c\n\tcc -c $<\n.c.o:\n";
+        let (_, m) = crate::parser::parse_makefile(data).unwrap();
+        let d = build(m, |_| false).unwrap();
+        assert_eq!(commands(&find(&d, "foo.o").recipe), vec!["cc -c $<"]);
+        assert!(find(&d, ".c.o").recipe.is_empty());
+    }
+
+    #[test]
+    fn test_escaped_hash() {
+        // the evaluator unescapes names, so each one is a single node
+        let data = "all: a\\#1 b\\#2\na\\#1 x &: y\n\tgen\nb\\#2:: z\n\tcp\n";
+        let (_, m) = crate::parser::parse_makefile(data).unwrap();
+        let sources = typed_arena::Arena::new();
+        let m = crate::eval::Evaluator::new(&sources).evaluate(m).unwrap();
+        let d = build(m, |_| false).unwrap();
+        assert_eq!(d.node_count(), 5);
+        assert_eq!(find(&d, "a#1").group, vec!["a#1", "x"]);
+        let b = index(&d, "b#2");
+        let rule = &d[b].double_colon[0];
+        assert_eq!(rule.prerequisites, vec!["z"]);
+        assert!(is_double_colon_out_of_date(&d, b, rule, &|_: &str| None));
+    }
+
+    #[test]
+    fn test_grouped_targets() {
+        let data = "all: foo.c foo.h\nfoo.h foo.c &: foo.y\n\tbison --defines=foo.h -o foo.c foo.y\nprog: foo.c\n";
+        let (_, m) = crate::parser::parse_makefile(data).unwrap();
+        let d = build(m, |_| false).unwrap();
+        assert_eq!(d.node_count(), 4);
+        assert_eq!(index(&d, "foo.h"), index(&d, "foo.c"));
+        let group = &d[index(&d, "foo.h")];
+        assert_eq!(group.group, vec!["foo.h", "foo.c"]);
+        assert_eq!(group.to_string(), "foo.h foo.c");
+        assert_eq!(commands(&group.recipe).len(), 1);
+        assert_eq!(d.edge_count(), 3);
+
+        let time = |s| Some(SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(s));
+        let mtime = |name: &str| match name {
+            "foo.y" => time(2),
+            "foo.h" => time(3),
+            "foo.c" => time(1),
+            "prog" => time(4),
+            _ => None,
+        };
+        let n = index(&d, "foo.c");
+        assert!(is_out_of_date(&d, n, &mtime));
+        assert!(is_out_of_date(&d, index(&d, "prog"), &mtime));
+
+        let mtime = |name: &str| match name {
+            "foo.y" => time(1),
+            "foo.h" => time(3),
+            "foo.c" => time(2),
+            "prog" => time(4),
+            _ => None,
+        };
+        assert!(!is_out_of_date(&d, n, &mtime));
+        assert!(!is_out_of_date(&d, index(&d, "prog"), &mtime));
+    }
 }
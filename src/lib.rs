@@ -1,7 +1,15 @@
 extern crate clap;
 extern crate daggy;
 extern crate nom;
+extern crate typed_arena;
 
+pub mod cst;
+pub mod eval;
+pub mod format;
+pub mod functions;
 pub mod graph;
+pub mod owned;
 pub mod parser;
+pub mod pattern;
 pub mod types;
+pub mod vpath;
@@ -1,43 +1,117 @@
-use std::fs::File;
-use std::io::prelude::*;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process;
 
-use clap::{App, Arg};
+use clap::{App, Arg, ArgMatches, SubCommand};
 use daggy::petgraph::dot::{Config, Dot};
-use nom::error::convert_error;
+use oxidize::eval::Evaluator;
+use oxidize::format::format;
 use oxidize::graph;
-use oxidize::parser;
+use typed_arena::Arena;
+
+// fail reports an error and exits with the status make uses for errors
+fn fail(e: String) -> ! {
+    eprintln!("oxidize: {}", e.replace('\n', "\noxidize: "));
+    process::exit(2)
+}
+
+// fmt formats each of the files given to the fmt subcommand in place. With
+// --check the files are left alone and the exit status is 1 if any of them
+// isn't formatted.
+fn fmt(matches: &ArgMatches) -> std::io::Result<()> {
+    let check = matches.is_present("check");
+    let mut unformatted = false;
+    for name in matches.values_of("files").into_iter().flatten() {
+        let source = fs::read_to_string(name).unwrap_or_else(|e| fail(format!("{}: {}", name, e)));
+        let formatted = format(name, &source).unwrap_or_else(|diagnostics| {
+            let messages: Vec<String> = diagnostics.iter().map(|d| d.to_string()).collect();
+            fail(messages.join("\n"))
+        });
+        if formatted == source {
+            continue;
+        }
+        if check {
+            println!("{} is not formatted", name);
+            unformatted = true;
+        } else {
+            fs::write(name, formatted)?;
+        }
+    }
+    if unformatted {
+        process::exit(1);
+    }
+    Ok(())
+}
 
 fn main() -> std::io::Result<()> {
-    let matches = App::new("oxidize")
-        .version("0.1.0")
-        .author("Dave Tucker <dave@dtucker.co.uk>")
-        .about("A fast, flexible, make alternative")
-        .arg(
-            Arg::with_name("file")
-                .short("f")
-                .long("file")
-                .takes_value(true)
-                .default_value("Makefile")
-                .help("File to read"),
-        )
-        .get_matches();
+    let matches =
+        App::new("oxidize")
+            .version("0.1.0")
+            .author("Dave Tucker <dave@dtucker.co.uk>")
+            .about("A fast, flexible, make alternative")
+            .arg(
+                Arg::with_name("file")
+                    .short("f")
+                    .long("file")
+                    .takes_value(true)
+                    .default_value("Makefile")
+                    .help("File to read"),
+            )
+            .arg(
+                Arg::with_name("include-dir")
+                    .short("I")
+                    .long("include-dir")
+                    .takes_value(true)
+                    .multiple(true)
+                    .number_of_values(1)
+                    .help("Directory to search for included Makefiles"),
+            )
+            .arg(
+                Arg::with_name("variables")
+                    .multiple(true)
+                    .help("Variables to set, in the form NAME=value"),
+            )
+            .subcommand(
+                SubCommand::with_name("fmt")
+                    .about("Rewrites Makefiles in the canonical style")
+                    .arg(Arg::with_name("check").long("check").help(
+                        "Exit with status 1 if any file isn't formatted, without changing it",
+                    ))
+                    .arg(
+                        Arg::with_name("files")
+                            .multiple(true)
+                            .default_value("Makefile")
+                            .help("Files to format"),
+                    ),
+            )
+            .get_matches();
+
+    if let Some(matches) = matches.subcommand_matches("fmt") {
+        return fmt(matches);
+    }
 
     let filename = matches.value_of("file").unwrap();
-    let mut file = File::open(filename)?;
-    let mut contents = String::new();
-    file.read_to_string(&mut contents)?;
+    let include_dirs = matches
+        .values_of("include-dir")
+        .map(|v| v.map(PathBuf::from).collect())
+        .unwrap_or_default();
 
-    let res = parser::parse_makefile(&contents);
-    match res {
-        Err(nom::Err::Error(e)) | Err(nom::Err::Failure(e)) => {
-            println!("{}", convert_error(&contents, e));
-            panic!();
-        }
-        Err(nom::Err::Incomplete(_)) => unreachable!(),
-        Ok((_, o)) => {
-            let dag = graph::from_makefile(o).unwrap();
-            println!("{:?}", Dot::with_config(&dag, &[Config::EdgeNoLabel]));
-            Ok(())
+    let sources = Arena::new();
+    let mut evaluator = Evaluator::new(&sources);
+    evaluator.include_dirs(include_dirs);
+    evaluator.define_environment(std::env::vars());
+    for v in matches.values_of("variables").into_iter().flatten() {
+        if let Err(e) = evaluator.define_command_line(v) {
+            fail(e);
         }
     }
+    let makefile = evaluator
+        .evaluate_file(Path::new(filename))
+        .unwrap_or_else(|e| fail(e));
+    let dag = evaluator
+        .search()
+        .and_then(|search| graph::from_makefile(makefile, &search))
+        .unwrap_or_else(|e| fail(e));
+    println!("{}", Dot::with_config(&dag, &[Config::EdgeNoLabel]));
+    Ok(())
 }
@@ -0,0 +1,432 @@
+// The types in this module mirror those in types, but own their text rather
+// than borrowing it from the source of the Makefile. They can outlive the
+// source, be kept in a cache or be sent to another thread.
+use std::fmt;
+
+use crate::types::{self, Assignment, Modifiers};
+
+fn strings(v: &[&str]) -> Vec<String> {
+    v.iter().map(|s| s.to_string()).collect()
+}
+
+// A Span is where something was written
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct Span {
+    pub file: String,
+    pub line: usize,
+    pub column: usize,
+}
+
+impl From<types::Span<'_>> for Span {
+    fn from(span: types::Span<'_>) -> Self {
+        Span {
+            file: span.file.to_string(),
+            line: span.line,
+            column: span.column,
+        }
+    }
+}
+
+impl fmt::Display for Span {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.file.is_empty() {
+            write!(f, "{}:{}", self.line, self.column)
+        } else {
+            write!(f, "{}:{}:{}", self.file, self.line, self.column)
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct Variable {
+    pub name: String,
+    pub assignment: Assignment,
+    pub value: Vec<String>,
+    pub define: bool,
+    pub modifiers: Modifiers,
+    pub span: Span,
+}
+
+impl From<&types::Variable<'_>> for Variable {
+    fn from(v: &types::Variable<'_>) -> Self {
+        Variable {
+            name: v.name.to_string(),
+            assignment: v.assignment,
+            value: strings(&v.value),
+            define: v.define,
+            modifiers: v.modifiers,
+            span: v.span.into(),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum RuleKind {
+    Explicit,
+    Pattern,
+    StaticPattern(String),
+}
+
+impl From<types::RuleKind<'_>> for RuleKind {
+    fn from(kind: types::RuleKind<'_>) -> Self {
+        match kind {
+            types::RuleKind::Explicit => RuleKind::Explicit,
+            types::RuleKind::Pattern => RuleKind::Pattern,
+            types::RuleKind::StaticPattern(p) => RuleKind::StaticPattern(p.to_string()),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct RecipeLine {
+    pub command: String,
+    pub silent: bool,
+    pub ignore_errors: bool,
+    pub always: bool,
+    pub span: Span,
+}
+
+impl From<&types::RecipeLine<'_>> for RecipeLine {
+    fn from(r: &types::RecipeLine<'_>) -> Self {
+        RecipeLine {
+            command: r.command.to_string(),
+            silent: r.silent,
+            ignore_errors: r.ignore_errors,
+            always: r.always,
+            span: r.span.into(),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct Prerequisite {
+    pub name: String,
+    pub span: Span,
+}
+
+impl From<&types::Prerequisite<'_>> for Prerequisite {
+    fn from(p: &types::Prerequisite<'_>) -> Self {
+        Prerequisite {
+            name: p.name.to_string(),
+            span: p.span.into(),
+        }
+    }
+}
+
+impl AsRef<str> for Prerequisite {
+    fn as_ref(&self) -> &str {
+        &self.name
+    }
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct Rule {
+    pub kind: RuleKind,
+    pub double_colon: bool,
+    pub grouped: bool,
+    pub targets: Vec<String>,
+    pub prerequsities: Vec<Prerequisite>,
+    pub order_only: Vec<Prerequisite>,
+    pub recipe: Vec<RecipeLine>,
+    pub span: Span,
+}
+
+impl From<&types::Rule<'_>> for Rule {
+    fn from(r: &types::Rule<'_>) -> Self {
+        Rule {
+            kind: r.kind.into(),
+            double_colon: r.double_colon,
+            grouped: r.grouped,
+            targets: strings(&r.targets),
+            prerequsities: r.prerequsities.iter().map(Prerequisite::from).collect(),
+            order_only: r.order_only.iter().map(Prerequisite::from).collect(),
+            recipe: r.recipe.iter().map(RecipeLine::from).collect(),
+            span: r.span.into(),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct TargetVariable {
+    pub targets: Vec<String>,
+    pub variable: Variable,
+    pub span: Span,
+}
+
+impl From<&types::TargetVariable<'_>> for TargetVariable {
+    fn from(v: &types::TargetVariable<'_>) -> Self {
+        TargetVariable {
+            targets: strings(&v.targets),
+            variable: (&v.variable).into(),
+            span: v.span.into(),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum Condition {
+    IfEq(String, String),
+    IfNeq(String, String),
+    IfDef(String),
+    IfNdef(String),
+}
+
+impl From<&types::Condition<'_>> for Condition {
+    fn from(c: &types::Condition<'_>) -> Self {
+        match *c {
+            types::Condition::IfEq(a, b) => Condition::IfEq(a.to_string(), b.to_string()),
+            types::Condition::IfNeq(a, b) => Condition::IfNeq(a.to_string(), b.to_string()),
+            types::Condition::IfDef(v) => Condition::IfDef(v.to_string()),
+            types::Condition::IfNdef(v) => Condition::IfNdef(v.to_string()),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct Conditional {
+    pub condition: Condition,
+    pub then: Vec<Statement>,
+    pub otherwise: Vec<Statement>,
+    pub span: Span,
+}
+
+impl From<&types::Conditional<'_>> for Conditional {
+    fn from(c: &types::Conditional<'_>) -> Self {
+        Conditional {
+            condition: (&c.condition).into(),
+            then: c.then.iter().map(Statement::from).collect(),
+            otherwise: c.otherwise.iter().map(Statement::from).collect(),
+            span: c.span.into(),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct Include {
+    pub names: Vec<String>,
+    pub optional: bool,
+    pub span: Span,
+}
+
+impl From<&types::Include<'_>> for Include {
+    fn from(i: &types::Include<'_>) -> Self {
+        Include {
+            names: strings(&i.names),
+            optional: i.optional,
+            span: i.span.into(),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct Vpath {
+    pub pattern: Option<String>,
+    pub directories: Vec<String>,
+    pub span: Span,
+}
+
+impl From<&types::Vpath<'_>> for Vpath {
+    fn from(v: &types::Vpath<'_>) -> Self {
+        Vpath {
+            pattern: v.pattern.map(String::from),
+            directories: strings(&v.directories),
+            span: v.span.into(),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct Export {
+    pub names: Vec<String>,
+    pub export: bool,
+    pub span: Span,
+}
+
+impl From<&types::Export<'_>> for Export {
+    fn from(e: &types::Export<'_>) -> Self {
+        Export {
+            names: strings(&e.names),
+            export: e.export,
+            span: e.span.into(),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct Undefine {
+    pub name: String,
+    pub r#override: bool,
+    pub span: Span,
+}
+
+impl From<&types::Undefine<'_>> for Undefine {
+    fn from(u: &types::Undefine<'_>) -> Self {
+        Undefine {
+            name: u.name.to_string(),
+            r#override: u.r#override,
+            span: u.span.into(),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct Recipe {
+    pub lines: Vec<RecipeLine>,
+    pub span: Span,
+}
+
+impl From<&types::Recipe<'_>> for Recipe {
+    fn from(r: &types::Recipe<'_>) -> Self {
+        Recipe {
+            lines: r.lines.iter().map(RecipeLine::from).collect(),
+            span: r.span.into(),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum Statement {
+    Variable(Variable),
+    Rule(Rule),
+    TargetVariable(TargetVariable),
+    Conditional(Conditional),
+    Include(Include),
+    Undefine(Undefine),
+    Export(Export),
+    Vpath(Vpath),
+    Recipe(Recipe),
+}
+
+impl From<&types::Statement<'_>> for Statement {
+    fn from(s: &types::Statement<'_>) -> Self {
+        match s {
+            types::Statement::Variable(v) => Statement::Variable(v.into()),
+            types::Statement::Rule(r) => Statement::Rule(r.into()),
+            types::Statement::TargetVariable(v) => Statement::TargetVariable(v.into()),
+            types::Statement::Conditional(c) => Statement::Conditional(c.into()),
+            types::Statement::Include(i) => Statement::Include(i.into()),
+            types::Statement::Undefine(u) => Statement::Undefine(u.into()),
+            types::Statement::Export(e) => Statement::Export(e.into()),
+            types::Statement::Vpath(v) => Statement::Vpath(v.into()),
+            types::Statement::Recipe(r) => Statement::Recipe(r.into()),
+        }
+    }
+}
+
+impl Statement {
+    // span returns where the statement was written
+    pub fn span(&self) -> &Span {
+        match self {
+            Statement::Variable(v) => &v.span,
+            Statement::Rule(r) => &r.span,
+            Statement::TargetVariable(v) => &v.span,
+            Statement::Conditional(c) => &c.span,
+            Statement::Include(i) => &i.span,
+            Statement::Undefine(u) => &u.span,
+            Statement::Export(e) => &e.span,
+            Statement::Vpath(v) => &v.span,
+            Statement::Recipe(r) => &r.span,
+        }
+    }
+}
+
+// Makefile is the owned form of types::Makefile. Makefiles read from
+// different files can be merged with extend.
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct Makefile {
+    pub statements: Vec<Statement>,
+}
+
+impl From<&types::Makefile<'_>> for Makefile {
+    fn from(m: &types::Makefile<'_>) -> Self {
+        Makefile {
+            statements: m.statements.iter().map(Statement::from).collect(),
+        }
+    }
+}
+
+impl From<types::Makefile<'_>> for Makefile {
+    fn from(m: types::Makefile<'_>) -> Self {
+        (&m).into()
+    }
+}
+
+impl Extend<Statement> for Makefile {
+    fn extend<T: IntoIterator<Item = Statement>>(&mut self, iter: T) {
+        self.statements.extend(iter)
+    }
+}
+
+impl Makefile {
+    // variables returns the variables defined outside of any conditional
+    pub fn variables(&self) -> Vec<&Variable> {
+        self.statements
+            .iter()
+            .filter_map(|s| match s {
+                Statement::Variable(v) => Some(v),
+                _ => None,
+            })
+            .collect()
+    }
+
+    // rules returns the rules defined outside of any conditional
+    pub fn rules(&self) -> Vec<&Rule> {
+        self.statements
+            .iter()
+            .filter_map(|s| match s {
+                Statement::Rule(r) => Some(r),
+                _ => None,
+            })
+            .collect()
+    }
+
+    // target_variables returns the target-specific and pattern-specific
+    // variables defined outside of any conditional
+    pub fn target_variables(&self) -> Vec<&TargetVariable> {
+        self.statements
+            .iter()
+            .filter_map(|s| match s {
+                Statement::TargetVariable(v) => Some(v),
+                _ => None,
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::parser::parse_file;
+
+    #[test]
+    fn test_from_borrowed() {
+        let m = {
+            let data = String::from("CC = gcc\nifdef CC\nall: main.o | obj\n\t@$(CC) -o $@ $^\nendif\n%.o: CFLAGS += -g\n");
+            let (_, m) = parse_file("Makefile", &data).unwrap();
+            Makefile::from(m)
+        };
+        // the owned Makefile outlives its source and can be sent to another thread
+        let m = std::thread::spawn(move || m).join().unwrap();
+
+        assert_eq!(m.variables()[0].name, "CC");
+        assert_eq!(m.variables()[0].value, vec!["gcc"]);
+        let rule = match &m.statements[1] {
+            Statement::Conditional(c) => match &c.then[0] {
+                Statement::Rule(r) => r,
+                s => panic!("expected a rule, found {:?}", s),
+            },
+            s => panic!("expected a conditional, found {:?}", s),
+        };
+        assert_eq!(rule.targets, vec!["all"]);
+        assert_eq!(rule.prerequsities[0].name, "main.o");
+        assert_eq!(rule.order_only[0].span.to_string(), "Makefile:3:15");
+        assert_eq!(rule.recipe[0].command, "$(CC) -o $@ $^");
+        assert!(rule.recipe[0].silent);
+        assert_eq!(m.target_variables()[0].targets, vec!["%.o"]);
+
+        let mut merged = Makefile::default();
+        merged.extend(m.statements.clone());
+        merged.extend(m.statements.clone());
+        assert_eq!(merged.statements.len(), 2 * m.statements.len());
+    }
+}
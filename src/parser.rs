@@ -1,18 +1,20 @@
 use nom::{
     branch::alt,
     bytes::complete::{tag, take_while},
-    character::complete::{line_ending, not_line_ending, space0, space1},
-    combinator::{map, opt},
-    error::{context, make_error, ErrorKind, VerboseError},
+    character::complete::{char, line_ending, not_line_ending, space0, space1},
+    combinator::{cut, map, opt},
+    error::{context, make_error, ErrorKind, VerboseError, VerboseErrorKind},
     multi::{many0, many1},
-    sequence::tuple,
+    sequence::{delimited, preceded, terminated, tuple},
     IResult,
 };
 
+use std::collections::HashMap;
+
 use crate::types::*;
 
 // parse_assignment_op maps an operator to an Assignment
-fn parse_assignment_op<'a>(i: &'a str) -> IResult<&'a str, Assignment, VerboseError<&'a str>> {
+pub(crate) fn parse_assignment_op(i: &str) -> IResult<&str, Assignment, VerboseError<&str>> {
     alt((
         map(tag("="), |_| Assignment::Recursive),
         map(tag("+="), |_| Assignment::Append),
@@ -26,39 +28,39 @@ fn parse_assignment_op<'a>(i: &'a str) -> IResult<&'a str, Assignment, VerboseEr
 // A variable name may be any sequence of characters not containing ‘:’, ‘#’, ‘=’, or whitespace
 // I am also including ? and ! as these can conflict with the assignment operator
 fn is_variable_name(c: char) -> bool {
-    match c {
-        ':' => false,
-        '#' => false,
-        '=' => false,
-        '!' => false,
-        '?' => false,
-        ' ' => false,
-        '\t' => false,
-        '\r' => false,
-        '\n' => false,
-        _ => true,
-    }
+    !matches!(c, ':' | '#' | '=' | '!' | '?' | ' ' | '\t' | '\r' | '\n')
 }
 
 // parse_variable_name takes valid variable name characters
-fn parse_variable_name<'a>(i: &'a str) -> IResult<&'a str, &'a str, VerboseError<&'a str>> {
-    take_while(is_variable_name)(i)
+fn parse_variable_name(i: &str) -> IResult<&str, &str, VerboseError<&str>> {
+    let (j, name) = take_while(is_variable_name)(i)?;
+    // a + right before the = is the append operator, as in CFLAGS+=-g
+    if name.ends_with('+') && j.starts_with('=') {
+        let end = name.len() - 1;
+        return Ok((&i[end..], &name[..end]));
+    }
+    Ok((j, name))
 }
 
 // Parse Variable
-fn parse_variable<'a>(i: &'a str) -> IResult<&'a str, Variable, VerboseError<&'a str>> {
+fn parse_variable(i: &str) -> IResult<&str, Variable<'_>, VerboseError<&str>> {
+    let (i, _) = space0(i)?;
     let (i, name) = parse_variable_name(i)?;
     let (i, _) = space0(i)?;
     let (i, operator) = parse_assignment_op(i)?;
     let (i, _) = space0(i)?;
 
-    let mut parts: Vec<&'a str> = Vec::new();
+    let mut parts: Vec<&str> = Vec::new();
     let (i, recipe) = not_line_ending(i)?;
     parts.push(recipe);
 
     let mut i = i;
     if recipe.ends_with('\\') {
         loop {
+            // a backslash at the very end of the file continues onto nothing
+            if i.is_empty() {
+                break;
+            }
             //println!("{}", i);
             let (j, _) = line_ending(i)?;
             //println!("{}", j);
@@ -73,16 +75,111 @@ fn parse_variable<'a>(i: &'a str) -> IResult<&'a str, Variable, VerboseError<&'a
         }
     }
     let (i, _) = many0(line_ending)(i)?;
+    // a comment runs to the end of the value, including any lines it continues onto
+    if let Some((n, idx)) = parts
+        .iter()
+        .enumerate()
+        .find_map(|(n, p)| find_comment(p).map(|idx| (n, idx)))
+    {
+        parts[n] = &parts[n][..idx];
+        parts.truncate(n + 1);
+    }
     Ok((
         i,
         Variable {
             name,
             assignment: operator,
             value: parts,
+            define: false,
+            modifiers: Modifiers::default(),
+            span: Span::default(),
         },
     ))
 }
 
+// find_comment returns the position of the # that starts a comment in line.
+// A # escaped with an odd number of backslashes is part of the text.
+pub(crate) fn find_comment(line: &str) -> Option<usize> {
+    let mut escaped = false;
+    for (idx, c) in line.char_indices() {
+        match c {
+            '#' if !escaped => return Some(idx),
+            '\\' => escaped = !escaped,
+            _ => escaped = false,
+        }
+    }
+    None
+}
+
+// unescape_hashes removes the escaping from the # characters left in text
+// once comments have been removed. Each # is preceded by an odd number of
+// backslashes, half of which, rounded down, are literal backslashes.
+pub(crate) fn unescape_hashes(text: &str) -> String {
+    let mut res = String::with_capacity(text.len());
+    let mut backslashes = 0;
+    for c in text.chars() {
+        match c {
+            '\\' => {
+                backslashes += 1;
+                continue;
+            }
+            '#' => backslashes /= 2,
+            _ => {}
+        }
+        res.push_str(&"\\".repeat(backslashes));
+        res.push(c);
+        backslashes = 0;
+    }
+    res.push_str(&"\\".repeat(backslashes));
+    res
+}
+
+// find_inline_recipe returns the position of the ; that starts an inline
+// recipe in a rule line. A ; inside a reference such as `$(shell a;b)` is
+// part of the reference.
+pub(crate) fn find_inline_recipe(line: &str) -> Option<usize> {
+    find_outside_references(line, ';')
+}
+
+// rule_kind works out from the targets of a rule whether it is a pattern
+// rule. A % inside a reference such as `$(SRCS:%.c=%.o)` doesn't make a
+// target a pattern; that is only known once it has been expanded.
+pub(crate) fn rule_kind<'a>(targets: &[&str]) -> Result<RuleKind<'a>, &'static str> {
+    let patterns = targets
+        .iter()
+        .filter(|t| find_outside_references(t, '%').is_some())
+        .count();
+    match patterns {
+        0 => Ok(RuleKind::Explicit),
+        n if n == targets.len() => Ok(RuleKind::Pattern),
+        _ => Err("mixed implicit and normal rules"),
+    }
+}
+
+// find_outside_references returns the position of the first wanted character
+// in line that isn't part of a reference
+fn find_outside_references(line: &str, wanted: char) -> Option<usize> {
+    let mut idx = 0;
+    while let Some(c) = line[idx..].chars().next() {
+        let rest = &line[idx..];
+        if rest.starts_with("$$") {
+            idx += 2;
+            continue;
+        }
+        if rest.starts_with("$(") || rest.starts_with("${") {
+            if let Ok((j, _)) = parse_reference(rest) {
+                idx = line.len() - j.len();
+                continue;
+            }
+        }
+        if c == wanted {
+            return Some(idx);
+        }
+        idx += c.len_utf8();
+    }
+    None
+}
+
 // The Make Manual says it should be a filename but can include wildcards
 // We'll use the cross-section of POSIX and Windows standards here
 // Wildcards:
@@ -90,49 +187,142 @@ fn parse_variable<'a>(i: &'a str) -> IResult<&'a str, Variable, VerboseError<&'a
 // ? is a single character foo.? ???bar???
 // [xyz]* or [a-z] is a list of characters
 fn is_target_character(c: char) -> bool {
-    match c {
-        '#' => false,
-        '%' => false,
-        ':' => false,
-        '|' => false,
-        '"' => false,
-        '<' => false,
-        '>' => false,
-        ' ' => false,
-        '\t' => false,
-        '\r' => false,
-        '\n' => false,
-        _ => true,
-    }
+    !matches!(
+        c,
+        '#' | ':' | ';' | '|' | '"' | '<' | '>' | ' ' | '\t' | '\r' | '\n'
+    )
 }
 
-fn parse_target_name<'a>(i: &'a str) -> IResult<&'a str, &'a str, VerboseError<&'a str>> {
-    take_while(is_target_character)(i)
+// parse_target_name takes target characters, stopping before the &: that
+// ends a list of grouped targets. An escaped \# is part of the name.
+fn parse_target_name(i: &str) -> IResult<&str, &str, VerboseError<&str>> {
+    let mut end = 0;
+    while let Some(c) = i[end..].chars().next() {
+        let rest = &i[end..];
+        // a reference is part of the name, even if it contains a space or a
+        // colon as in `$(SRCS:.c=.o)`
+        if rest.starts_with("$(") || rest.starts_with("${") {
+            let line = &rest[..rest.find(['\r', '\n']).unwrap_or(rest.len())];
+            if let Ok((j, _)) = parse_reference(line) {
+                end += line.len() - j.len();
+                continue;
+            }
+        }
+        let backslashes = i[..end].len() - i[..end].trim_end_matches('\\').len();
+        let escaped = c == '#' && backslashes % 2 == 1;
+        if (!is_target_character(c) && !escaped) || rest.starts_with("&:") {
+            break;
+        }
+        end += c.len_utf8();
+    }
+    // as with a variable value, a trailing backslash is not a name
+    if &i[..end] == "\\" && end == i.len() {
+        return Ok((i, ""));
+    }
+    Ok((&i[end..], &i[..end]))
 }
 
-fn parse_comment<'a>(i: &'a str) -> IResult<&'a str, &'a str, VerboseError<&'a str>> {
-    let (i, _) = many1(tuple((tag("#"), not_line_ending, many0(line_ending))))(i)?;
+fn parse_comment(i: &str) -> IResult<&str, &str, VerboseError<&str>> {
+    let (i, _) = many1(tuple((
+        space0,
+        tag("#"),
+        not_line_ending,
+        many0(line_ending),
+    )))(i)?;
     Ok((i, ""))
 }
 
-fn parse_recipe<'a>(i: &'a str) -> IResult<&'a str, &'a str, VerboseError<&'a str>> {
-    let (i, _) = space1(i)?;
-    let (i, recipe) = opt(not_line_ending)(i)?;
-    let (i, _) = many0(line_ending)(i)?;
-    Ok((
-        i,
-        match recipe {
-            Some(r) => r,
-            None => "",
-        },
-    ))
+// parse_blank_line takes a line containing nothing but whitespace
+fn parse_blank_line(i: &str) -> IResult<&str, &str, VerboseError<&str>> {
+    let (j, _) = space0(i)?;
+    if j.is_empty() {
+        return Ok((j, i));
+    }
+    let (j, _) = line_ending(j)?;
+    Ok((j, i))
+}
+
+// parse_recipe parses a recipe line, which must start with the recipe prefix.
+// The prefix is a tab unless .RECIPEPREFIX has been set.
+fn parse_recipe<'a>(
+    prefix: char,
+) -> impl Fn(&'a str) -> IResult<&'a str, &'a str, VerboseError<&'a str>> {
+    move |i: &'a str| {
+        let (i, _) = char(prefix)(i)?;
+        let (i, _) = space0(i)?;
+        let (i, recipe) = opt(not_line_ending)(i)?;
+        let (i, _) = many0(line_ending)(i)?;
+        Ok((i, recipe.unwrap_or_default()))
+    }
+}
+
+// parse_recipe_line splits the @, - and + prefixes, in any order and
+// separated by any whitespace, from the start of a recipe line
+fn parse_recipe_line(line: &str) -> RecipeLine<'_> {
+    let mut res = RecipeLine {
+        command: "",
+        silent: false,
+        ignore_errors: false,
+        always: false,
+        span: Span::default(),
+    };
+    for (idx, c) in line.char_indices() {
+        match c {
+            '@' => res.silent = true,
+            '-' => res.ignore_errors = true,
+            '+' => res.always = true,
+            ' ' | '\t' => {}
+            _ => {
+                res.command = &line[idx..];
+                break;
+            }
+        }
+    }
+    res
 }
 
-fn parse_recipes<'a>(i: &'a str) -> IResult<&'a str, Vec<&'a str>, VerboseError<&'a str>> {
-    many0(parse_recipe)(i)
+// parse_recipes parses the recipe lines following a rule. Lines continuing
+// a command after a backslash don't need the recipe prefix and take the
+// prefixes of that command rather than having their own.
+fn parse_recipes<'a>(
+    prefix: char,
+) -> impl Fn(&'a str) -> IResult<&'a str, Vec<RecipeLine<'a>>, VerboseError<&'a str>> {
+    move |i: &'a str| {
+        let mut i = i;
+        let mut res: Vec<RecipeLine> = Vec::new();
+        loop {
+            match res.last() {
+                Some(prev) if prev.command.ends_with('\\') => {
+                    let (j, _) = opt(char(prefix))(i)?;
+                    let (j, _) = space0(j)?;
+                    let (j, line) = not_line_ending(j)?;
+                    let (j, _) = many0(line_ending)(j)?;
+                    res.push(RecipeLine {
+                        command: line,
+                        ..*prev
+                    });
+                    i = j;
+                }
+                _ => match parse_recipe(prefix)(i) {
+                    Ok((j, line)) => {
+                        res.push(parse_recipe_line(line));
+                        i = j;
+                    }
+                    Err(nom::Err::Error(_)) => {
+                        // blank lines and comments may appear among the recipe lines
+                        match alt((parse_blank_line, parse_comment))(i) {
+                            Ok((j, _)) if !i.is_empty() => i = j,
+                            _ => return Ok((i, res)),
+                        }
+                    }
+                    Err(e) => return Err(e),
+                },
+            }
+        }
+    }
 }
 
-fn parse_target_names<'a>(i: &'a str) -> IResult<&'a str, &'a str, VerboseError<&'a str>> {
+fn parse_target_names(i: &str) -> IResult<&str, &str, VerboseError<&str>> {
     let (i, _) = space0(i)?;
     let (i, target) = parse_target_name(i)?;
     let (i, _) = space0(i)?;
@@ -142,11 +332,11 @@ fn parse_target_names<'a>(i: &'a str) -> IResult<&'a str, &'a str, VerboseError<
     }
 }
 
-fn parse_target_list<'a>(i: &'a str) -> IResult<&'a str, Vec<&'a str>, VerboseError<&'a str>> {
+fn parse_target_list(i: &str) -> IResult<&str, Vec<&str>, VerboseError<&str>> {
     many1(parse_target_names)(i)
 }
 
-fn parse_prereqs<'a>(i: &'a str) -> IResult<&'a str, &'a str, VerboseError<&'a str>> {
+fn parse_prereqs(i: &str) -> IResult<&str, &str, VerboseError<&str>> {
     let (i, _) = space0(i)?;
     let (i, prereq) = parse_target_name(i)?;
     let (i, _) = space0(i)?;
@@ -158,226 +348,1309 @@ fn parse_prereqs<'a>(i: &'a str) -> IResult<&'a str, &'a str, VerboseError<&'a s
     }
 }
 
-fn parse_prereqs_list<'a>(i: &'a str) -> IResult<&'a str, Vec<&'a str>, VerboseError<&'a str>> {
+fn parse_prereqs_list(i: &str) -> IResult<&str, Vec<&str>, VerboseError<&str>> {
     many0(parse_prereqs)(i)
 }
 
-fn parse_rule<'a>(i: &'a str) -> IResult<&'a str, Rule, VerboseError<&'a str>> {
-    let (i, name) = context("target", parse_target_list)(i)?;
-    let (i, _) = space0(i)?;
-    let (i, _) = context("delimiter", tag(":"))(i)?;
-    let (i, _) = space0(i)?;
-    let (i, prereqs) = context("prereqs", parse_prereqs_list)(i)?;
-    let (i, _) = line_ending(i)?;
-    let (i, recipe) = context("recipe", parse_recipes)(i)?;
+// parse_rule parses a rule and the recipe lines that follow it
+fn parse_rule<'a>(
+    prefix: char,
+) -> impl Fn(&'a str) -> IResult<&'a str, Rule<'a>, VerboseError<&'a str>> {
+    move |i: &'a str| {
+        let start = i;
+        let (i, name) = context("target", parse_target_list)(i)?;
+        let (i, _) = space0(i)?;
+        let (i, delimiter) = context(
+            "delimiter",
+            alt((tag("&::"), tag("&:"), tag("::"), tag(":"))),
+        )(i)?;
+        let (i, _) = space0(i)?;
+        let (i, prereqs) = context("prereqs", parse_prereqs_list)(i)?;
+        // a second colon makes this a static pattern rule, where the first list
+        // is the target pattern
+        let (i, static_prereqs) = opt(preceded(
+            tag(":"),
+            preceded(space0, context("prereqs", parse_prereqs_list)),
+        ))(i)?;
+        let (i, order_only) = opt(preceded(
+            tag("|"),
+            preceded(space0, context("order-only prereqs", parse_prereqs_list)),
+        ))(i)?;
+        // a recipe may start on the same line, after a semicolon
+        let (i, inline) = opt(preceded(tag(";"), preceded(space0, not_line_ending)))(i)?;
+        // comments end the rule line, but are part of an inline recipe
+        let (i, _) = match inline {
+            Some(_) if i.is_empty() => (i, i),
+            Some(_) => line_ending(i)?,
+            None => parse_end_of_line(i)?,
+        };
+        let (i, mut recipe) = context("recipe", parse_recipes(prefix))(i)?;
+        if let Some(inline) = inline {
+            recipe.insert(0, parse_recipe_line(inline));
+        }
+
+        // targets that are references are classified again once the
+        // evaluator has expanded them, which is when mixed targets are reported
+        let (kind, prereqs) = match static_prereqs {
+            Some(static_prereqs) => match prereqs.as_slice() {
+                [target_pattern] if target_pattern.contains(['%', '$']) => {
+                    (RuleKind::StaticPattern(target_pattern), static_prereqs)
+                }
+                _ => return Err(failure(start, "target pattern contains no '%'")),
+            },
+            None => (rule_kind(&name).unwrap_or(RuleKind::Explicit), prereqs),
+        };
+
+        Ok((
+            i,
+            Rule {
+                kind,
+                double_colon: delimiter.ends_with("::"),
+                grouped: delimiter.starts_with('&'),
+                targets: name,
+                prerequsities: prerequisites(prereqs),
+                order_only: prerequisites(order_only.unwrap_or_default()),
+                recipe,
+                span: Span::default(),
+            },
+        ))
+    }
+}
+
+// prerequisites wraps prerequisite names, leaving their spans for parse_statements to fill in
+fn prerequisites(names: Vec<&str>) -> Vec<Prerequisite<'_>> {
+    names
+        .into_iter()
+        .map(|name| Prerequisite {
+            name,
+            span: Span::default(),
+        })
+        .collect()
+}
 
+// parse_target_variable parses a variable definition that applies to the
+// targets before the colon, like `debug: CFLAGS += -g`
+fn parse_target_variable(i: &str) -> IResult<&str, TargetVariable<'_>, VerboseError<&str>> {
+    let (i, targets) = parse_target_list(i)?;
+    let (i, _) = space0(i)?;
+    let (i, _) = alt((tag("::"), tag(":")))(i)?;
+    let (i, variable) = parse_assignment(i)?;
     Ok((
         i,
-        Rule {
-            targets: name,
-            prerequsities: prereqs,
-            recipe,
+        TargetVariable {
+            targets,
+            variable,
+            span: Span::default(),
         },
     ))
 }
 
-pub fn parse_makefile<'a>(i: &'a str) -> IResult<&'a str, Makefile, VerboseError<&'a str>> {
-    let mut i = i;
-    let mut res = Makefile {
-        rules: Vec::new(),
-        variables: Vec::new(),
-    };
-    loop {
-        if i.is_empty() {
-            break;
-        }
-        match line_ending::<_, VerboseError<&str>>(i) {
-            Ok((j, _)) => i = j,
-            Err(_) => match parse_comment(i) {
-                Ok((j, _)) => {
-                    i = j;
-                }
-                Err(_) => match parse_variable(i) {
-                    Ok((j, o)) => {
-                        i = j;
-                        res.variables.push(o);
-                    }
-                    Err(_) => match parse_rule(i) {
-                        Ok((j, o)) => {
-                            i = j;
-                            res.rules.push(o);
-                        }
-                        Err(e) => {
-                            return Err(e);
-                        }
-                    },
-                },
-            },
-        }
-    }
-    Ok((i, res))
+// failure builds an error that stops parsing, with a message describing the problem
+fn failure<'a>(i: &'a str, message: &'static str) -> nom::Err<VerboseError<&'a str>> {
+    nom::Err::Failure(VerboseError {
+        errors: vec![(i, VerboseErrorKind::Context(message))],
+    })
 }
 
-#[cfg(test)]
-mod test {
-    use super::*;
-    use nom::error::convert_error;
-    use std::fs;
-
-    macro_rules! test_op {
-        ($name:ident, $i:expr, $o:expr) => {
-            #[test]
-            fn $name() {
-                let res = parse_assignment_op($i);
-                match res {
-                    Ok((_, o)) => {
-                        assert_eq!($o, o);
-                    }
-                    Err(nom::Err::Error(e)) | Err(nom::Err::Failure(e)) => {
-                        println!("{}", convert_error($i, e));
-                        panic!();
-                    }
-                    Err(nom::Err::Incomplete(_)) => unreachable!(),
-                }
-            }
-        };
+// parse_end_of_line takes an optional comment followed by a line ending or the end of input
+fn parse_end_of_line(i: &str) -> IResult<&str, &str, VerboseError<&str>> {
+    let (i, _) = space0(i)?;
+    let (i, _) = opt(tuple((tag("#"), not_line_ending)))(i)?;
+    if i.is_empty() || i == "\\" {
+        return Ok(("", i));
     }
+    line_ending(i)
+}
 
-    test_op!(test_parse_cond_op, "?=", Assignment::Conditional);
-    test_op!(test_parse_simple_op1, ":=", Assignment::Simple);
-    test_op!(test_parse_simple_op2, "::=", Assignment::Simple);
-    test_op!(test_parse_shell, "!=", Assignment::Shell);
-    test_op!(test_parse_recursive_op, "=", Assignment::Recursive);
-    test_op!(test_parse_append_op, "+=", Assignment::Append);
-
-    #[test]
-    fn test_is_variable_name() {
-        if is_variable_name('?') {
-            panic!("not a var name")
-        }
-        if is_variable_name('#') {
-            panic!("not a var name")
-        }
-        if !is_variable_name('2') {
-            panic!("it's a valid char")
-        }
-        if !is_variable_name('a') {
-            panic!("it's a valid char")
+// keyword matches a directive name as long as it isn't the start of a longer word
+fn keyword<'a>(
+    word: &'static str,
+) -> impl Fn(&'a str) -> IResult<&'a str, &'a str, VerboseError<&'a str>> {
+    move |i: &'a str| {
+        let (j, k) = tag(word)(i)?;
+        match j.chars().next() {
+            None | Some(' ') | Some('\t') | Some('\r') | Some('\n') | Some('(') | Some('#') => {
+                Ok((j, k))
+            }
+            _ => Err(nom::Err::Error(make_error(i, ErrorKind::Tag))),
         }
     }
+}
 
-    #[test]
-    fn test_parse_variable_name() {
-        assert_eq!(parse_variable_name("bar#"), Ok(("#", "bar")));
-        assert_eq!(parse_variable_name("b?ar"), Ok(("?ar", "b")));
-        assert_eq!(parse_variable_name("b? ar"), Ok(("? ar", "b")));
-    }
-
-    #[test]
-    fn test_parse_variable() {
-        assert_eq!(
-            parse_variable("foo := bar\r\n"),
-            Ok((
-                "",
-                Variable {
-                    name: "foo",
-                    assignment: Assignment::Simple,
-                    value: vec!("bar")
-                }
-            ))
-        );
-        assert_eq!(
-            parse_variable("foo=\n"),
-            Ok((
-                "",
-                Variable {
-                    name: "foo",
-                    assignment: Assignment::Recursive,
-                    value: vec!("")
-                }
-            ))
-        );
+// take_balanced takes characters up to the first `stop` that is not nested
+// inside parentheses or braces, so that `$(call f,a)` stays in one piece
+fn take_balanced<'a>(
+    stop: char,
+) -> impl Fn(&'a str) -> IResult<&'a str, &'a str, VerboseError<&'a str>> {
+    move |i: &'a str| {
+        let mut depth = 0;
+        for (idx, c) in i.char_indices() {
+            match c {
+                '\r' | '\n' => break,
+                c if c == stop && depth == 0 => return Ok((&i[idx..], &i[..idx])),
+                '(' | '{' => depth += 1,
+                ')' | '}' => depth -= 1,
+                _ => {}
+            }
+        }
+        Err(nom::Err::Error(make_error(i, ErrorKind::TakeUntil)))
     }
+}
 
-    #[test]
-    fn test_parse_multiline_variable() {
-        let data = "CFLAGS = $(CDEBUG) -I. -I$(srcdir) $(DEFS) \\\n\t\t-DDEF_AR_FILE=\\\"$(DEF_AR_FILE)\\\" \\\n\t\t-DDEFBLOCKING=$(DEFBLOCKING)\n";
-        let res = parse_variable(data);
-        match res {
-            Ok((i, o)) => {
-                assert_eq!(o.name, "CFLAGS");
-                assert_eq!(o.assignment, Assignment::Recursive);
-                assert_eq!(o.value.len(), 3);
-                assert_eq!(i, "");
+// parse_word takes a whitespace separated word, keeping variable references
+// such as `$(addsuffix .mk, a b)` in one piece
+fn parse_word(i: &str) -> IResult<&str, &str, VerboseError<&str>> {
+    let mut depth = 0;
+    let mut end = i.len();
+    for (idx, c) in i.char_indices() {
+        match c {
+            '\r' | '\n' => {
+                end = idx;
+                break;
             }
-            Err(nom::Err::Error(e)) | Err(nom::Err::Failure(e)) => {
-                println!("{}", convert_error(data, e));
-                panic!();
+            ' ' | '\t' | '#' if depth == 0 => {
+                end = idx;
+                break;
             }
-            Err(nom::Err::Incomplete(_)) => unreachable!(),
+            '(' | '{' => depth += 1,
+            ')' | '}' => depth -= 1,
+            _ => {}
         }
     }
-
-    #[test]
-    fn test_parse_target_name() {
-        assert_eq!(parse_target_name("foo.c"), Ok(("", "foo.c")));
-        assert_eq!(parse_target_name("foo.*"), Ok(("", "foo.*")));
-        assert_eq!(parse_target_name("$(SRCS)"), Ok(("", "$(SRCS)")));
-        assert_eq!(parse_target_name("foo.[abc]??"), Ok(("", "foo.[abc]??")));
-        assert_eq!(parse_target_name("/usr/src/foo"), Ok(("", "/usr/src/foo")));
-        assert_eq!(
-            parse_target_name(".\\file\\foo.ps1"),
-            Ok(("", ".\\file\\foo.ps1"))
-        );
-        assert_eq!(parse_target_name("file<"), Ok(("<", "file")));
+    match end {
+        0 => Err(nom::Err::Error(make_error(i, ErrorKind::Eof))),
+        _ => Ok((&i[end..], &i[..end])),
     }
+}
 
-    #[test]
-    fn test_parse_comment() {
-        assert_eq!(parse_comment("#comment\n"), Ok(("", "")));
-        assert_eq!(
-        parse_comment("# Set this to rtapelib.o unless you defined NO_REMOTE,\n# in which case make it empty.\n"),
-         Ok(("",""))
-    );
+// parse_define parses a multi-line variable definition. The body is kept
+// verbatim, up to the endef matching the define, so it may contain nested
+// define blocks for use with $(eval).
+fn parse_define(i: &str) -> IResult<&str, Variable<'_>, VerboseError<&str>> {
+    let start = i;
+    let (i, _) = space0(i)?;
+    let (i, _) = keyword("define")(i)?;
+    let (i, _) = space1(i)?;
+    let (i, name) = parse_variable_name(i)?;
+    if name.is_empty() {
+        return Err(nom::Err::Error(make_error(i, ErrorKind::Eof)));
     }
+    let (i, _) = space0(i)?;
+    let (i, assignment) = opt(parse_assignment_op)(i)?;
+    let (i, _) = context("define", cut(parse_end_of_line))(i)?;
 
-    #[test]
-    fn test_parse_recipe_single() {
-        let data = "\tshar $(SRCS) $(AUX) | compress\n";
-        let res = parse_recipe(data);
-        match res {
-            Ok((_, o)) => {
-                assert_eq!(o, "shar $(SRCS) $(AUX) | compress");
-            }
-            Err(nom::Err::Error(e)) | Err(nom::Err::Failure(e)) => {
-                println!("{}", convert_error(data, e));
-                panic!();
+    let mut depth = 0;
+    let mut rest = i;
+    loop {
+        if rest.is_empty() {
+            return Err(failure(start, "missing endef"));
+        }
+        if let Ok((j, _)) = preceded(space0, keyword("endef"))(rest) {
+            if depth == 0 {
+                let body = &i[..i.len() - rest.len()];
+                let body = body.strip_suffix('\n').unwrap_or(body);
+                let body = body.strip_suffix('\r').unwrap_or(body);
+                let (j, _) = context("endef", cut(parse_end_of_line))(j)?;
+                return Ok((
+                    j,
+                    Variable {
+                        name,
+                        assignment: assignment.unwrap_or(Assignment::Recursive),
+                        value: vec![body],
+                        define: true,
+                        modifiers: Modifiers::default(),
+                        span: Span::default(),
+                    },
+                ));
             }
-            Err(nom::Err::Incomplete(_)) => unreachable!(),
+            depth -= 1;
+        } else if preceded(space0, keyword("define"))(rest).is_ok() {
+            depth += 1;
         }
+        let (j, _) = not_line_ending(rest)?;
+        let (j, _) = opt(line_ending)(j)?;
+        rest = j;
     }
+}
 
-    #[test]
-    fn test_parse_recipe_list() {
-        let data = "\tcc -c main.c \\\n\tfoo bar baz\n";
-        let res = parse_recipes(data);
-        match res {
-            Ok((_, o)) => {
-                assert_eq!(o.len(), 2);
-                assert_eq!(o[0], "cc -c main.c \\");
-                assert_eq!(o[1], "foo bar baz");
+// parse_modifiers takes any export, override and private keywords before a variable definition
+fn parse_modifiers(i: &str) -> IResult<&str, Modifiers, VerboseError<&str>> {
+    let mut i = i;
+    let mut modifiers = Modifiers::default();
+    loop {
+        let res = terminated(
+            preceded(
+                space0,
+                alt((keyword("export"), keyword("override"), keyword("private"))),
+            ),
+            space1,
+        )(i);
+        i = match res {
+            Ok((j, "export")) => {
+                modifiers.export = true;
+                j
             }
-            Err(nom::Err::Error(e)) | Err(nom::Err::Failure(e)) => {
-                println!("{}", convert_error(data, e));
-                panic!();
+            Ok((j, "override")) => {
+                modifiers.r#override = true;
+                j
+            }
+            Ok((j, _)) => {
+                modifiers.private = true;
+                j
+            }
+            Err(_) => return Ok((i, modifiers)),
+        };
+    }
+}
+
+// parse_assignment parses a variable definition, either on a single line or
+// in a define block, along with any modifiers written before it
+fn parse_assignment(i: &str) -> IResult<&str, Variable<'_>, VerboseError<&str>> {
+    let (j, modifiers) = parse_modifiers(i)?;
+    let res = match parse_define(j) {
+        Err(nom::Err::Error(_)) => parse_variable(j),
+        res => res,
+    };
+    match res {
+        Ok((j, mut v)) if !v.name.is_empty() => {
+            v.modifiers = modifiers;
+            Ok((j, v))
+        }
+        // a variable may itself be called export, override or private
+        Ok(_) | Err(nom::Err::Error(_)) if modifiers != Modifiers::default() => {
+            match parse_define(i) {
+                Err(nom::Err::Error(_)) => parse_variable(i),
+                res => res,
             }
-            Err(nom::Err::Incomplete(_)) => unreachable!(),
         }
+        Ok(_) => Err(nom::Err::Error(make_error(i, ErrorKind::Eof))),
+        Err(e) => Err(e),
     }
+}
 
-    #[test]
-    fn test_parse_prereq_literal() {
-        assert_eq!(parse_prereqs("main.c"), Ok(("", "main.c")));
+// parse_undefine parses the undefine directive
+fn parse_undefine(i: &str) -> IResult<&str, Undefine<'_>, VerboseError<&str>> {
+    let (i, modifiers) = parse_modifiers(i)?;
+    let (i, _) = space0(i)?;
+    let (i, _) = keyword("undefine")(i)?;
+    let (i, _) = space0(i)?;
+    let (i, name) = parse_variable_name(i)?;
+    if name.is_empty() {
+        return Err(nom::Err::Error(make_error(i, ErrorKind::Eof)));
+    }
+    let (i, _) = parse_end_of_line(i)?;
+    Ok((
+        i,
+        Undefine {
+            name,
+            r#override: modifiers.r#override,
+            span: Span::default(),
+        },
+    ))
+}
+
+// parse_export parses the export and unexport directives when they aren't
+// part of a variable definition
+fn parse_export(i: &str) -> IResult<&str, Export<'_>, VerboseError<&str>> {
+    let (i, _) = space0(i)?;
+    let (i, directive) = alt((keyword("export"), keyword("unexport")))(i)?;
+    let (i, names) = many0(preceded(space0, parse_word))(i)?;
+    let (i, _) = parse_end_of_line(i)?;
+    Ok((
+        i,
+        Export {
+            names,
+            export: directive == "export",
+            span: Span::default(),
+        },
+    ))
+}
+
+// parse_include parses the include, -include and sinclude directives
+fn parse_include(i: &str) -> IResult<&str, Include<'_>, VerboseError<&str>> {
+    let (i, _) = space0(i)?;
+    let (i, directive) = alt((keyword("include"), keyword("-include"), keyword("sinclude")))(i)?;
+    let (i, names) = many0(preceded(space0, parse_word))(i)?;
+    let (i, _) = parse_end_of_line(i)?;
+    Ok((
+        i,
+        Include {
+            names,
+            optional: directive != "include",
+            span: Span::default(),
+        },
+    ))
+}
+
+// parse_vpath parses the vpath directive in any of its three forms
+fn parse_vpath(i: &str) -> IResult<&str, Vpath<'_>, VerboseError<&str>> {
+    let (i, _) = space0(i)?;
+    let (i, _) = keyword("vpath")(i)?;
+    let (i, pattern) = opt(preceded(space0, parse_word))(i)?;
+    let (i, directories) = many0(preceded(space0, parse_word))(i)?;
+    let (i, _) = parse_end_of_line(i)?;
+    Ok((
+        i,
+        Vpath {
+            pattern,
+            directories,
+            span: Span::default(),
+        },
+    ))
+}
+
+// parse_conditional_keyword recognises the start of any line that belongs to a conditional
+pub(crate) fn parse_conditional_keyword(i: &str) -> IResult<&str, &str, VerboseError<&str>> {
+    let (i, _) = space0(i)?;
+    alt((
+        keyword("ifeq"),
+        keyword("ifneq"),
+        keyword("ifdef"),
+        keyword("ifndef"),
+        keyword("else"),
+        keyword("endif"),
+    ))(i)
+}
+
+// Parse the arguments of ifeq and ifneq in the form (a,b)
+fn parse_paren_args(i: &str) -> IResult<&str, (&str, &str), VerboseError<&str>> {
+    let (i, _) = tag("(")(i)?;
+    let (i, a) = take_balanced(',')(i)?;
+    let (i, _) = tag(",")(i)?;
+    let (i, b) = take_balanced(')')(i)?;
+    let (i, _) = tag(")")(i)?;
+    Ok((i, (a.trim(), b.trim())))
+}
+
+// Parse a single or double quoted argument
+fn parse_quoted(i: &str) -> IResult<&str, &str, VerboseError<&str>> {
+    alt((
+        delimited(
+            tag("\""),
+            take_while(|c| c != '"' && c != '\r' && c != '\n'),
+            tag("\""),
+        ),
+        delimited(
+            tag("'"),
+            take_while(|c| c != '\'' && c != '\r' && c != '\n'),
+            tag("'"),
+        ),
+    ))(i)
+}
+
+// Parse the arguments of ifeq and ifneq in the form "a" "b"
+fn parse_quoted_args(i: &str) -> IResult<&str, (&str, &str), VerboseError<&str>> {
+    let (i, a) = parse_quoted(i)?;
+    let (i, _) = space1(i)?;
+    let (i, b) = parse_quoted(i)?;
+    Ok((i, (a, b)))
+}
+
+// parse_condition parses the opening line of a conditional
+fn parse_condition(i: &str) -> IResult<&str, Condition<'_>, VerboseError<&str>> {
+    let (i, _) = space0(i)?;
+    let (i, directive) = alt((
+        keyword("ifeq"),
+        keyword("ifneq"),
+        keyword("ifdef"),
+        keyword("ifndef"),
+    ))(i)?;
+    let (i, _) = space0(i)?;
+    match directive {
+        "ifeq" | "ifneq" => {
+            let (i, (a, b)) = context(
+                "conditional arguments",
+                cut(alt((parse_paren_args, parse_quoted_args))),
+            )(i)?;
+            let (i, _) = context("end of conditional", cut(parse_end_of_line))(i)?;
+            if directive == "ifeq" {
+                Ok((i, Condition::IfEq(a, b)))
+            } else {
+                Ok((i, Condition::IfNeq(a, b)))
+            }
+        }
+        _ => {
+            let (j, name) = take_while(|c| c != '#' && c != '\r' && c != '\n')(i)?;
+            let name = name.trim();
+            if name.is_empty() {
+                return Err(failure(i, "variable name"));
+            }
+            let (j, _) = parse_end_of_line(j)?;
+            if directive == "ifdef" {
+                Ok((j, Condition::IfDef(name)))
+            } else {
+                Ok((j, Condition::IfNdef(name)))
+            }
+        }
+    }
+}
+
+// parse_else parses an else line, which may start another conditional
+fn parse_else(i: &str) -> IResult<&str, Option<Condition<'_>>, VerboseError<&str>> {
+    let (i, _) = space0(i)?;
+    let (i, _) = keyword("else")(i)?;
+    context(
+        "else",
+        cut(alt((
+            map(parse_end_of_line, |_| None),
+            map(parse_condition, Some),
+        ))),
+    )(i)
+}
+
+fn parse_endif(i: &str) -> IResult<&str, &str, VerboseError<&str>> {
+    let (i, _) = space0(i)?;
+    let (i, _) = keyword("endif")(i)?;
+    parse_end_of_line(i)
+}
+
+// parse_conditional_body parses the statements following a condition up to
+// and including the endif that closes it
+fn parse_conditional_body<'a>(
+    start: &'a str,
+    i: &'a str,
+    condition: Condition<'a>,
+    state: &mut State<'a>,
+) -> IResult<&'a str, Conditional<'a>, VerboseError<&'a str>> {
+    // either branch may carry on the recipe of a rule before the conditional.
+    // Only the branch that is taken can change the prefix, so the other one
+    // is parsed with the prefix from before the conditional.
+    let recipe = state.recipe;
+    let prefix = state.prefix;
+    let (i, then) = parse_statements(i, state)?;
+    let then_recipe = std::mem::replace(&mut state.recipe, recipe);
+    let then_prefix = std::mem::replace(&mut state.prefix, prefix);
+    if let Ok((i, _)) = parse_endif(i) {
+        state.recipe |= then_recipe;
+        state.prefix = then_prefix;
+        return Ok((
+            i,
+            Conditional {
+                condition,
+                then,
+                otherwise: Vec::new(),
+                span: state.span(start),
+            },
+        ));
+    }
+    let (i, otherwise) = match parse_else(i) {
+        Ok((j, None)) => {
+            let (j, otherwise) = parse_statements(j, state)?;
+            match parse_endif(j) {
+                Ok((j, _)) => (j, otherwise),
+                Err(_) => return Err(failure(start, "missing endif")),
+            }
+        }
+        Ok((j, Some(c))) => {
+            let (j, nested) = parse_conditional_body(i, j, c, state)?;
+            (j, vec![Statement::Conditional(nested)])
+        }
+        Err(nom::Err::Error(_)) => return Err(failure(start, "missing endif")),
+        Err(e) => return Err(e),
+    };
+    state.recipe |= then_recipe;
+    if then_prefix != prefix {
+        state.prefix = then_prefix;
+    }
+    Ok((
+        i,
+        Conditional {
+            condition,
+            then,
+            otherwise,
+            span: state.span(start),
+        },
+    ))
+}
+
+fn parse_conditional<'a>(
+    i: &'a str,
+    state: &mut State<'a>,
+) -> IResult<&'a str, Conditional<'a>, VerboseError<&'a str>> {
+    let (j, condition) = parse_condition(i)?;
+    state.conditionals.push((state.recipe, false, false));
+    let res = parse_conditional_body(i, j, condition, state);
+    state.conditionals.pop();
+    // once the condition has been read any error in the body is final
+    res.map_err(|e| match e {
+        nom::Err::Error(e) => nom::Err::Failure(e),
+        e => e,
+    })
+}
+
+// recipe_prefix returns the recipe prefix the parser takes from an
+// assignment to .RECIPEPREFIX. Inside a conditional, or when the value has
+// to be expanded, it is only known once the Makefile has been evaluated,
+// which gives it in prefixes by the line the assignment is on.
+pub(crate) fn recipe_prefix(
+    v: &Variable,
+    conditional: bool,
+    prefixes: &HashMap<usize, char>,
+) -> Option<char> {
+    if let Some(prefix) = prefixes.get(&v.span.line) {
+        return Some(*prefix);
+    }
+    let value = v.value.concat();
+    if conditional || value.contains('$') {
+        return None;
+    }
+    Some(value.chars().next().unwrap_or('\t'))
+}
+
+// State is carried from one statement to the next. It holds the text being
+// parsed, so spans can be worked out from the slices the parsers return, and
+// the recipe prefix, which changes when .RECIPEPREFIX is assigned, as
+// decided by recipe_prefix. When recover is set errors are collected in
+// diagnostics rather than returned. recipe is set while lines starting with the prefix are still part of the
+// recipe of the last rule. conditionals holds a entry for each open
+// conditional. When they are read a line at a time it is whether recipe was
+// set before it, whether any of its branches so far left it set and whether
+// it has a final else.
+pub(crate) struct State<'a> {
+    file: &'a str,
+    text: &'a str,
+    // the offset of the start of each line
+    lines: Vec<usize>,
+    pub(crate) prefix: char,
+    prefixes: HashMap<usize, char>,
+    pub(crate) recipe: bool,
+    conditionals: Vec<(bool, bool, bool)>,
+    recover: bool,
+    diagnostics: Vec<Diagnostic<'a>>,
+}
+
+impl<'a> State<'a> {
+    pub(crate) fn new(file: &'a str, text: &'a str) -> Self {
+        let lines = std::iter::once(0)
+            .chain(text.match_indices('\n').map(|(idx, _)| idx + 1))
+            .collect();
+        State {
+            file,
+            text,
+            lines,
+            prefix: '\t',
+            prefixes: HashMap::new(),
+            recipe: false,
+            conditionals: Vec::new(),
+            recover: false,
+            diagnostics: Vec::new(),
+        }
+    }
+
+    // span returns where i starts, ignoring any indentation. i must be a
+    // slice of the text; anything else has no line or column.
+    pub(crate) fn span(&self, i: &str) -> Span<'a> {
+        let i = i.trim_start_matches([' ', '\t']);
+        let start = self.text.as_ptr() as usize;
+        let pos = i.as_ptr() as usize;
+        if pos < start || pos > start + self.text.len() {
+            return Span {
+                file: self.file,
+                ..Span::default()
+            };
+        }
+        let offset = pos - start;
+        let line = match self.lines.binary_search(&offset) {
+            Ok(n) => n,
+            Err(n) => n - 1,
+        };
+        Span {
+            file: self.file,
+            line: line + 1,
+            column: self.text[self.lines[line]..offset].chars().count() + 1,
+        }
+    }
+
+    // conditional_line keeps track of whether the recipe is carried on when
+    // reading the conditional directive keyword, followed by rest, on its own.
+    // As in parse_conditional_body, either branch may carry it on.
+    pub(crate) fn conditional_line(&mut self, keyword: &str, rest: &str) {
+        match keyword {
+            "else" => {
+                if let Some((before, carried, otherwise)) = self.conditionals.last_mut() {
+                    *carried |= self.recipe;
+                    *otherwise = rest.trim().is_empty() || rest.trim_start().starts_with('#');
+                    self.recipe = *before;
+                }
+            }
+            "endif" => {
+                if let Some((before, carried, otherwise)) = self.conditionals.pop() {
+                    self.recipe |= carried || (before && !otherwise);
+                }
+            }
+            _ => self.conditionals.push((self.recipe, false, false)),
+        }
+    }
+
+    // diagnostic describes the error e from parsing the statement starting at i
+    pub(crate) fn diagnostic(
+        &self,
+        i: &'a str,
+        e: nom::Err<VerboseError<&'a str>>,
+    ) -> Diagnostic<'a> {
+        let e = match e {
+            nom::Err::Failure(e) => e,
+            // nothing could parse the line at all
+            _ => {
+                return Diagnostic {
+                    span: self.span(i),
+                    message: "missing separator".to_string(),
+                }
+            }
+        };
+        let context = e.errors.iter().find_map(|(_, kind)| match kind {
+            VerboseErrorKind::Context(c) => Some(*c),
+            _ => None,
+        });
+        let message = match (e.errors.first(), context) {
+            (Some((_, VerboseErrorKind::Context(c))), _) => c.to_string(),
+            (_, Some(c)) => format!("invalid {}", c),
+            (_, None) => "syntax error".to_string(),
+        };
+        Diagnostic {
+            span: self.span(e.errors.first().map_or(i, |(j, _)| j)),
+            message,
+        }
+    }
+}
+
+// skip_statement skips the logical line starting at i, along with any
+// recipe lines after it, so parsing can carry on after an error
+pub(crate) fn skip_statement(i: &str, prefix: char) -> &str {
+    let mut i = i;
+    loop {
+        let (line, rest) = match i.find('\n') {
+            Some(idx) => (&i[..idx], &i[idx + 1..]),
+            None => (i, ""),
+        };
+        i = rest;
+        if !line.trim_end_matches('\r').ends_with('\\') {
+            break;
+        }
+    }
+    while i.starts_with(prefix) {
+        i = i.find('\n').map_or("", |idx| &i[idx + 1..]);
+    }
+    i
+}
+
+// parse_statements parses statements until the end of the input or an else
+// or endif that ends the enclosing conditional
+fn parse_statements<'a>(
+    i: &'a str,
+    state: &mut State<'a>,
+) -> IResult<&'a str, Vec<Statement<'a>>, VerboseError<&'a str>> {
+    let mut i = i;
+    let mut res = Vec::new();
+    loop {
+        if i.is_empty() {
+            break;
+        }
+        // in a recipe, anything after the prefix is passed to the shell
+        if !state.recipe || !i.starts_with(state.prefix) {
+            if let Ok((_, "else")) | Ok((_, "endif")) = parse_conditional_keyword(i) {
+                break;
+            }
+            if let Ok((j, _)) = parse_blank_line(i) {
+                i = j;
+                continue;
+            }
+            if let Ok((j, _)) = parse_comment(i) {
+                i = j;
+                continue;
+            }
+        }
+        let diagnostics = state.diagnostics.len();
+        match parse_statement(i, state) {
+            Ok((j, o)) => {
+                i = j;
+                res.push(o);
+            }
+            Err(nom::Err::Incomplete(n)) => return Err(nom::Err::Incomplete(n)),
+            Err(e) if state.recover => {
+                // a conditional that failed is parsed again line by line, so
+                // anything found inside it so far would be reported twice
+                state.diagnostics.truncate(diagnostics);
+                let d = state.diagnostic(i, e);
+                state.diagnostics.push(d);
+                i = skip_statement(i, state.prefix);
+            }
+            Err(e) => return Err(e),
+        }
+    }
+    Ok((i, res))
+}
+
+// parse_statement parses a single statement, filling in the spans of it and its parts
+pub(crate) fn parse_statement<'a>(
+    i: &'a str,
+    state: &mut State<'a>,
+) -> IResult<&'a str, Statement<'a>, VerboseError<&'a str>> {
+    if state.recipe && i.starts_with(state.prefix) {
+        let (j, mut lines) = context("recipe", parse_recipes(state.prefix))(i)?;
+        for r in lines.iter_mut() {
+            r.span = state.span(r.command);
+        }
+        let span = state.span(i);
+        return Ok((j, Statement::Recipe(Recipe { lines, span })));
+    }
+    match parse_conditional(i, state) {
+        Ok((j, o)) => return Ok((j, Statement::Conditional(o))),
+        Err(nom::Err::Error(_)) => {}
+        Err(e) => return Err(e),
+    }
+    let after_rule = std::mem::replace(&mut state.recipe, false);
+    match parse_assignment(i) {
+        Ok((j, mut o)) => {
+            o.span = state.span(i);
+            if o.name == ".RECIPEPREFIX" {
+                let conditional = !state.conditionals.is_empty();
+                state.prefix =
+                    recipe_prefix(&o, conditional, &state.prefixes).unwrap_or(state.prefix);
+            }
+            return Ok((j, Statement::Variable(o)));
+        }
+        Err(nom::Err::Error(_)) => {}
+        Err(e) => return Err(e),
+    }
+    if let Ok((j, mut o)) = parse_undefine(i) {
+        o.span = state.span(i);
+        return Ok((j, Statement::Undefine(o)));
+    }
+    if let Ok((j, mut o)) = parse_export(i) {
+        o.span = state.span(i);
+        return Ok((j, Statement::Export(o)));
+    }
+    if let Ok((j, mut o)) = parse_include(i) {
+        o.span = state.span(i);
+        return Ok((j, Statement::Include(o)));
+    }
+    if let Ok((j, mut o)) = parse_vpath(i) {
+        o.span = state.span(i);
+        return Ok((j, Statement::Vpath(o)));
+    }
+    if let Ok((j, mut o)) = parse_target_variable(i) {
+        o.span = state.span(i);
+        o.variable.span = state.span(o.variable.name);
+        return Ok((j, Statement::TargetVariable(o)));
+    }
+    // a line indented with spaces that isn't anything else was most likely
+    // meant to be part of a recipe, even if it would parse as a rule when it
+    // follows one
+    let indented = i.starts_with(' ') && !i.lines().next().unwrap_or_default().trim().is_empty();
+    let (j, mut o) = match parse_rule(state.prefix)(i) {
+        res if indented && (after_rule || matches!(res, Err(nom::Err::Error(_)))) => {
+            return Err(failure(
+                i,
+                "recipe line starts with spaces, did you mean a tab?",
+            ))
+        }
+        res => res?,
+    };
+    o.span = state.span(i);
+    for p in o.prerequsities.iter_mut().chain(o.order_only.iter_mut()) {
+        p.span = state.span(p.name);
+    }
+    for r in o.recipe.iter_mut() {
+        r.span = state.span(r.command);
+    }
+    state.recipe = true;
+    Ok((j, Statement::Rule(o)))
+}
+
+pub fn parse_makefile(i: &str) -> IResult<&str, Makefile<'_>, VerboseError<&str>> {
+    parse_file("", i)
+}
+
+// parse_file parses the makefile read from file, which is recorded in the
+// span of everything in it
+pub fn parse_file<'a>(
+    file: &'a str,
+    i: &'a str,
+) -> IResult<&'a str, Makefile<'a>, VerboseError<&'a str>> {
+    let (i, statements) = parse_statements(i, &mut State::new(file, i))?;
+    if !i.is_empty() {
+        // parse_statements only stops early at an else or endif
+        return Err(failure(i, "else or endif without a matching conditional"));
+    }
+    Ok((i, Makefile { statements }))
+}
+
+// parse_recovering parses the makefile read from file without stopping at the
+// first error. Each line that can't be parsed is reported and skipped, and
+// the Makefile holds everything else.
+pub fn parse_recovering<'a>(file: &'a str, i: &'a str) -> (Makefile<'a>, Vec<Diagnostic<'a>>) {
+    parse_with_prefixes(file, i, HashMap::new())
+}
+
+// parse_with_prefixes is parse_recovering with the recipe prefixes set by
+// the .RECIPEPREFIX assignments on the lines in prefixes
+pub(crate) fn parse_with_prefixes<'a>(
+    file: &'a str,
+    i: &'a str,
+    prefixes: HashMap<usize, char>,
+) -> (Makefile<'a>, Vec<Diagnostic<'a>>) {
+    let mut state = State::new(file, i);
+    state.recover = true;
+    state.prefixes = prefixes;
+    let mut statements = Vec::new();
+    let mut i = i;
+    while let Ok((j, s)) = parse_statements(i, &mut state) {
+        statements.extend(s);
+        if j.is_empty() {
+            break;
+        }
+        state.diagnostics.push(Diagnostic {
+            span: state.span(j),
+            message: "else or endif without a matching conditional".to_string(),
+        });
+        i = skip_statement(j, state.prefix);
+    }
+    (Makefile { statements }, state.diagnostics)
+}
+
+// function_arity returns the number of arguments taken by a builtin
+// function, or 0 if it takes any number. Commas after the last argument are
+// part of it, so `$(info a, b)` prints `a, b`.
+fn function_arity(name: &str) -> Option<usize> {
+    let n = match name {
+        "strip" | "sort" | "words" | "firstword" | "lastword" | "dir" | "notdir" | "suffix"
+        | "basename" | "wildcard" | "realpath" | "abspath" | "error" | "warning" | "info"
+        | "shell" | "origin" | "flavor" | "eval" | "value" => 1,
+        "findstring" | "filter" | "filter-out" | "word" | "addsuffix" | "addprefix" | "join"
+        | "file" => 2,
+        "subst" | "patsubst" | "wordlist" | "foreach" | "if" | "let" => 3,
+        "intcmp" => 5,
+        "and" | "or" | "call" => 0,
+        _ => return None,
+    };
+    Some(n)
+}
+
+// parse_text parses text up to the end of input, one of the characters in
+// stop or the closing bracket of open. Brackets of the same kind as open
+// nest, so a `,` or `)` inside them is literal.
+fn parse_text<'a>(
+    i: &'a str,
+    stop: &[char],
+    open: Option<char>,
+) -> IResult<&'a str, Vec<Expr<'a>>, VerboseError<&'a str>> {
+    let close = match open {
+        Some('(') => Some(')'),
+        Some('{') => Some('}'),
+        _ => None,
+    };
+    let mut exprs = Vec::new();
+    let mut depth = 0;
+    let mut literal = i;
+    let mut i = i;
+    while let Some(c) = i.chars().next() {
+        if depth == 0 && (stop.contains(&c) || Some(c) == close) {
+            break;
+        }
+        match c {
+            '$' => {
+                let len = literal.len() - i.len();
+                if len > 0 {
+                    exprs.push(Expr::Literal(&literal[..len]));
+                }
+                let (j, expr) = parse_reference(i)?;
+                exprs.extend(expr);
+                i = j;
+                literal = i;
+                continue;
+            }
+            c if Some(c) == open => depth += 1,
+            c if Some(c) == close => depth -= 1,
+            _ => {}
+        }
+        i = &i[c.len_utf8()..];
+    }
+    let len = literal.len() - i.len();
+    if len > 0 {
+        exprs.push(Expr::Literal(&literal[..len]));
+    }
+    Ok((i, exprs))
+}
+
+// parse_reference parses a reference starting at a `$`. A `$` at the end of
+// the text refers to nothing.
+fn parse_reference<'a>(i: &'a str) -> IResult<&'a str, Option<Expr<'a>>, VerboseError<&'a str>> {
+    let start = i;
+    let (i, _) = char('$')(i)?;
+    let (open, close) = match i.chars().next() {
+        None => return Ok((i, None)),
+        Some('$') => return Ok((&i[1..], Some(Expr::Literal(&i[..1])))),
+        Some('(') => ('(', ')'),
+        Some('{') => ('{', '}'),
+        Some(c) => {
+            let len = c.len_utf8();
+            return Ok((
+                &i[len..],
+                Some(Expr::Reference(vec![Expr::Literal(&i[..len])])),
+            ));
+        }
+    };
+    let i = &i[1..];
+    let closed = |j: &'a str| match j.strip_prefix(close) {
+        Some(j) => Ok(j),
+        None => Err(failure(start, "unterminated variable reference")),
+    };
+
+    // a function call is a function name followed by whitespace
+    let function = &i[..i.find([' ', '\t', close]).unwrap_or(i.len())];
+    if let Some(arity) = function_arity(function) {
+        let j = &i[function.len()..];
+        if j.starts_with([' ', '\t']) {
+            let mut j = j.trim_start_matches([' ', '\t']);
+            let mut args = Vec::new();
+            loop {
+                let stop: &[char] = if arity == 0 || args.len() + 1 < arity {
+                    &[',']
+                } else {
+                    &[]
+                };
+                let (k, arg) = parse_text(j, stop, Some(open))?;
+                args.push(arg);
+                match k.strip_prefix(',') {
+                    Some(k) => j = k,
+                    None => {
+                        j = k;
+                        break;
+                    }
+                }
+            }
+            return Ok((closed(j)?, Some(Expr::Call { function, args })));
+        }
+    }
+
+    let (j, mut name) = parse_text(i, &[':'], Some(open))?;
+    if let Some(k) = j.strip_prefix(':') {
+        let (l, from) = parse_text(k, &['='], Some(open))?;
+        if let Some(l) = l.strip_prefix('=') {
+            let (l, to) = parse_text(l, &[], Some(open))?;
+            return Ok((closed(l)?, Some(Expr::Substitution { name, from, to })));
+        }
+        // without an = the colon is part of the name
+        name.push(Expr::Literal(&j[..1]));
+        name.extend(from);
+        return Ok((closed(l)?, Some(Expr::Reference(name))));
+    }
+    Ok((closed(j)?, Some(Expr::Reference(name))))
+}
+
+// parse_expression parses text such as the value of a variable into the
+// literals, references and function calls it is made of
+pub fn parse_expression(i: &str) -> IResult<&str, Vec<Expr<'_>>, VerboseError<&str>> {
+    parse_text(i, &[], None)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use nom::error::convert_error;
+    use std::fs;
+
+    fn names<'a>(prerequisites: &[Prerequisite<'a>]) -> Vec<&'a str> {
+        prerequisites.iter().map(|p| p.name).collect()
+    }
+
+    macro_rules! test_op {
+        ($name:ident, $i:expr, $o:expr) => {
+            #[test]
+            fn $name() {
+                let res = parse_assignment_op($i);
+                match res {
+                    Ok((_, o)) => {
+                        assert_eq!($o, o);
+                    }
+                    Err(nom::Err::Error(e)) | Err(nom::Err::Failure(e)) => {
+                        println!("{}", convert_error($i, e));
+                        panic!();
+                    }
+                    Err(nom::Err::Incomplete(_)) => unreachable!(),
+                }
+            }
+        };
+    }
+
+    test_op!(test_parse_cond_op, "?=", Assignment::Conditional);
+    test_op!(test_parse_simple_op1, ":=", Assignment::Simple);
+    test_op!(test_parse_simple_op2, "::=", Assignment::Simple);
+    test_op!(test_parse_shell, "!=", Assignment::Shell);
+    test_op!(test_parse_recursive_op, "=", Assignment::Recursive);
+    test_op!(test_parse_append_op, "+=", Assignment::Append);
+
+    #[test]
+    fn test_is_variable_name() {
+        if is_variable_name('?') {
+            panic!("not a var name")
+        }
+        if is_variable_name('#') {
+            panic!("not a var name")
+        }
+        if !is_variable_name('2') {
+            panic!("it's a valid char")
+        }
+        if !is_variable_name('a') {
+            panic!("it's a valid char")
+        }
+    }
+
+    #[test]
+    fn test_parse_variable_name() {
+        assert_eq!(parse_variable_name("bar#"), Ok(("#", "bar")));
+        assert_eq!(parse_variable_name("b?ar"), Ok(("?ar", "b")));
+        assert_eq!(parse_variable_name("b? ar"), Ok(("? ar", "b")));
+        assert_eq!(parse_variable_name("b+=c"), Ok(("+=c", "b")));
+        assert_eq!(parse_variable_name("b+ = c"), Ok((" = c", "b+")));
+    }
+
+    #[test]
+    fn test_parse_variable() {
+        assert_eq!(
+            parse_variable("foo := bar\r\n"),
+            Ok((
+                "",
+                Variable {
+                    name: "foo",
+                    assignment: Assignment::Simple,
+                    value: vec!("bar"),
+                    define: false,
+                    modifiers: Modifiers::default(),
+                    span: Span::default(),
+                }
+            ))
+        );
+        assert_eq!(
+            parse_variable("foo=\n"),
+            Ok((
+                "",
+                Variable {
+                    name: "foo",
+                    assignment: Assignment::Recursive,
+                    value: vec!(""),
+                    define: false,
+                    modifiers: Modifiers::default(),
+                    span: Span::default(),
+                }
+            ))
+        );
+    }
+
+    #[test]
+    fn test_parse_multiline_variable() {
+        let data = "CFLAGS = $(CDEBUG) -I. -I$(srcdir) $(DEFS) \\\n\t\t-DDEF_AR_FILE=\\\"$(DEF_AR_FILE)\\\" \\\n\t\t-DDEFBLOCKING=$(DEFBLOCKING)\n";
+        let res = parse_variable(data);
+        match res {
+            Ok((i, o)) => {
+                assert_eq!(o.name, "CFLAGS");
+                assert_eq!(o.assignment, Assignment::Recursive);
+                assert_eq!(o.value.len(), 3);
+                assert_eq!(i, "");
+            }
+            Err(nom::Err::Error(e)) | Err(nom::Err::Failure(e)) => {
+                println!("{}", convert_error(data, e));
+                panic!();
+            }
+            Err(nom::Err::Incomplete(_)) => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn test_parse_target_name() {
+        assert_eq!(parse_target_name("foo.c"), Ok(("", "foo.c")));
+        assert_eq!(parse_target_name("foo.*"), Ok(("", "foo.*")));
+        assert_eq!(parse_target_name("$(SRCS)"), Ok(("", "$(SRCS)")));
+        assert_eq!(parse_target_name("foo.[abc]??"), Ok(("", "foo.[abc]??")));
+        assert_eq!(parse_target_name("/usr/src/foo"), Ok(("", "/usr/src/foo")));
+        assert_eq!(
+            parse_target_name(".\\file\\foo.ps1"),
+            Ok(("", ".\\file\\foo.ps1"))
+        );
+        assert_eq!(parse_target_name("file<"), Ok(("<", "file")));
+        // a # is escaped by an odd number of backslashes
+        assert_eq!(unescape_hashes("a\\#b\\\\\\#c\\d\\"), "a#b\\#c\\d\\");
+        assert_eq!(parse_target_name("a\\#b"), Ok(("", "a\\#b")));
+        assert_eq!(parse_target_name("a\\\\#b"), Ok(("#b", "a\\\\")));
+        assert_eq!(parse_target_name("a\\\\\\#b"), Ok(("", "a\\\\\\#b")));
+        // references are kept whole, whatever they contain
+        assert_eq!(
+            parse_target_name("$(SRCS:.c=.o): x"),
+            Ok((": x", "$(SRCS:.c=.o)"))
+        );
+        assert_eq!(
+            parse_target_name("$(addprefix obj/, a.o b.o) c"),
+            Ok((" c", "$(addprefix obj/, a.o b.o)"))
+        );
+        assert_eq!(parse_target_name("${a b}.o;"), Ok((";", "${a b}.o")));
+        assert_eq!(parse_target_name("$(a b\n)"), Ok((" b\n)", "$(a")));
+    }
+
+    #[test]
+    fn test_rule_kind() {
+        assert_eq!(rule_kind(&["a.o", "b.o"]), Ok(RuleKind::Explicit));
+        assert_eq!(rule_kind(&["%.o", "%.d"]), Ok(RuleKind::Pattern));
+        assert_eq!(rule_kind(&["$(SRCS:%.c=%.o)"]), Ok(RuleKind::Explicit));
+        assert_eq!(
+            rule_kind(&["%.o", "a.o"]),
+            Err("mixed implicit and normal rules")
+        );
+    }
+
+    #[test]
+    fn test_find_inline_recipe() {
+        assert_eq!(find_inline_recipe("all: a ; echo"), Some(7));
+        assert_eq!(find_inline_recipe("all: $(shell a;b) c"), None);
+        assert_eq!(find_inline_recipe("all: ${x;y};z"), Some(11));
+        assert_eq!(find_inline_recipe("all: $$(a;b)"), Some(9));
+        assert_eq!(find_inline_recipe("all: $(a;b"), Some(8));
+    }
+
+    #[test]
+    fn test_parse_comment() {
+        assert_eq!(parse_comment("#comment\n"), Ok(("", "")));
+        assert_eq!(
+        parse_comment("# Set this to rtapelib.o unless you defined NO_REMOTE,\n# in which case make it empty.\n"),
+         Ok(("",""))
+    );
+    }
+
+    #[test]
+    fn test_parse_recipe_single() {
+        let data = "\tshar $(SRCS) $(AUX) | compress\n";
+        let res = parse_recipe('\t')(data);
+        match res {
+            Ok((_, o)) => {
+                assert_eq!(o, "shar $(SRCS) $(AUX) | compress");
+            }
+            Err(nom::Err::Error(e)) | Err(nom::Err::Failure(e)) => {
+                println!("{}", convert_error(data, e));
+                panic!();
+            }
+            Err(nom::Err::Incomplete(_)) => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn test_parse_recipe_list() {
+        let data = "\tcc -c main.c \\\n\tfoo bar baz\n";
+        let res = parse_recipes('\t')(data);
+        match res {
+            Ok((_, o)) => {
+                assert_eq!(o.len(), 2);
+                assert_eq!(o[0].command, "cc -c main.c \\");
+                assert_eq!(o[1].command, "foo bar baz");
+            }
+            Err(nom::Err::Error(e)) | Err(nom::Err::Failure(e)) => {
+                println!("{}", convert_error(data, e));
+                panic!();
+            }
+            Err(nom::Err::Incomplete(_)) => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn test_parse_recipe_prefixes() {
+        let data = "\t@echo hi\n\t-rm foo\n\t+ @ -$(MAKE) -C sub \\\n\t  -k\n\tcc\n";
+        let res = parse_recipes('\t')(data);
+        match res {
+            Ok((_, o)) => {
+                let flags: Vec<_> = o
+                    .iter()
+                    .map(|l| (l.command, l.silent, l.ignore_errors, l.always))
+                    .collect();
+                assert_eq!(
+                    flags,
+                    vec![
+                        ("echo hi", true, false, false),
+                        ("rm foo", false, true, false),
+                        ("$(MAKE) -C sub \\", true, true, true),
+                        ("-k", true, true, true),
+                        ("cc", false, false, false),
+                    ]
+                );
+            }
+            Err(nom::Err::Error(e)) | Err(nom::Err::Failure(e)) => {
+                println!("{}", convert_error(data, e));
+                panic!();
+            }
+            Err(nom::Err::Incomplete(_)) => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn test_parse_recipe_prefix() {
+        let data = "a:\n\tone\n.RECIPEPREFIX = >\nb:\n>two \\\n  more\n>@three\n.RECIPEPREFIX =\nc:\n\tfour\n";
+        let res = parse_makefile(data);
+        match res {
+            Ok((_, o)) => {
+                let recipes: Vec<Vec<&str>> = o
+                    .rules()
+                    .iter()
+                    .map(|r| r.recipe.iter().map(|l| l.command).collect())
+                    .collect();
+                assert_eq!(
+                    recipes,
+                    vec![vec!["one"], vec!["two \\", "more", "three"], vec!["four"]]
+                );
+            }
+            Err(nom::Err::Error(e)) | Err(nom::Err::Failure(e)) => {
+                println!("{}", convert_error(data, e));
+                panic!();
+            }
+            Err(nom::Err::Incomplete(_)) => unreachable!(),
+        }
+
+        // a tab isn't a recipe prefix once it has been changed
+        assert!(parse_makefile(".RECIPEPREFIX = >\nb:\n\ttwo\n").is_err());
+        // but it is still used when the change is inside a conditional or
+        // needs expanding, which the evaluator checks
+        for data in &[
+            "ifdef NOPE\n.RECIPEPREFIX = >\nendif\nall:\n\techo hi\n",
+            "P = >\n.RECIPEPREFIX = $(P)\nall:\n\techo hi\n",
+        ] {
+            let (_, m) = parse_makefile(data).unwrap();
+            assert_eq!(m.rules()[0].recipe[0].command, "echo hi");
+        }
+        // until the evaluator gives the prefix set on that line
+        let prefixes = [(2, '>')].iter().cloned().collect();
+        let data = "ifndef NOPE\n.RECIPEPREFIX = >\nendif\nall:\n>echo hi\n";
+        let (m, diagnostics) = parse_with_prefixes("", data, prefixes);
+        assert!(diagnostics.is_empty());
+        assert_eq!(m.rules()[0].recipe[0].command, "echo hi");
+    }
+
+    #[test]
+    fn test_parse_recipe_spaces() {
+        let data = "all: foo\n    cc -o foo foo.c\n";
+        match parse_makefile(data) {
+            Err(nom::Err::Failure(e)) => assert_eq!(
+                e.errors,
+                vec![(
+                    "    cc -o foo foo.c\n",
+                    VerboseErrorKind::Context(
+                        "recipe line starts with spaces, did you mean a tab?"
+                    )
+                )]
+            ),
+            res => panic!("unexpected result {:?}", res),
+        }
+        // after a rule the line is part of its recipe even if it looks like a rule
+        let data = "all: b\n    echo a: b\n";
+        match parse_makefile(data) {
+            Err(nom::Err::Failure(e)) => assert_eq!(e.errors[0].0, "    echo a: b\n"),
+            res => panic!("unexpected result {:?}", res),
+        }
+        assert!(parse_makefile("all: b\n\n  ifdef X\n\techo\n  endif\n  baz: qux\n").is_err());
+        // indented lines that mean something else are still fine
+        assert!(parse_makefile("all: foo\n  FOO = bar\n  baz: qux\n").is_ok());
+        assert!(parse_makefile("all: foo\n  ifdef X\n  endif\n  export FOO\n").is_ok());
+    }
+
+    #[test]
+    fn test_parse_prereq_literal() {
+        assert_eq!(parse_prereqs("main.c"), Ok(("", "main.c")));
         assert_eq!(parse_prereqs(" main.c"), Ok(("", "main.c")));
         assert_eq!(parse_prereqs("main.c "), Ok(("", "main.c")));
         assert_eq!(parse_prereqs(" main.c "), Ok(("", "main.c")));
@@ -424,7 +1697,7 @@ mod test {
     #[test]
     fn test_parse_target_with_prereq() {
         let data = "main.o : main.c defs.h\n\tcc -c main.c\n";
-        let res = parse_rule(data);
+        let res = parse_rule('\t')(data);
         match res {
             Ok((_, o)) => {
                 assert!(o.targets.contains(&"main.o"));
@@ -432,7 +1705,203 @@ mod test {
                 assert_eq!(o.prerequsities[0], "main.c");
                 assert_eq!(o.prerequsities[1], "defs.h");
                 assert_eq!(o.recipe.len(), 1);
-                assert_eq!(o.recipe[0], "cc -c main.c");
+                assert_eq!(o.recipe[0].command, "cc -c main.c");
+            }
+            Err(nom::Err::Error(e)) | Err(nom::Err::Failure(e)) => {
+                println!("{}", convert_error(data, e));
+                panic!();
+            }
+            Err(nom::Err::Incomplete(_)) => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn test_parse_target_no_prereq() {
+        let data =
+        "clean :\n\trm edit main.o kbd.o command.o display.o \\\n\t\tinsert.o search.o files.o utils.o\n";
+        let res = parse_rule('\t')(data);
+        match res {
+            Ok((_, o)) => {
+                assert!(o.targets.contains(&"clean"));
+                assert_eq!(o.prerequsities.len(), 0);
+                assert_eq!(o.recipe.len(), 2);
+            }
+            Err(nom::Err::Error(e)) | Err(nom::Err::Failure(e)) => {
+                println!("{}", convert_error(data, e));
+                panic!();
+            }
+            Err(nom::Err::Incomplete(_)) => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn test_parse_target_split_prereqs_and_recipe() {
+        let data = "edit : main.o kbd.o command.o display.o \\\n\tinsert.o search.o files.o utils.o\n\tcc -o edit main.o kbd.o command.o display.o \\\n\tinsert.o search.o files.o utils.o\n";
+        let res = parse_rule('\t')(data);
+        match res {
+            Ok((_, o)) => {
+                assert!(o.targets.contains(&"edit"));
+                assert!(o.prerequsities.iter().any(|p| p.name == "main.o"));
+                assert!(o.prerequsities.iter().any(|p| p.name == "utils.o"));
+                assert_eq!(o.prerequsities.len(), 8);
+                assert_eq!(o.recipe.len(), 2);
+            }
+            Err(nom::Err::Error(e)) | Err(nom::Err::Failure(e)) => {
+                println!("{}", convert_error(data, e));
+                panic!();
+            }
+            Err(nom::Err::Incomplete(_)) => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn test_parse_pattern_rule() {
+        let data = "%.o : %.c $(HEADERS)\n\t$(CC) -c $< -o $@\n";
+        let res = parse_rule('\t')(data);
+        match res {
+            Ok((_, o)) => {
+                assert_eq!(o.kind, RuleKind::Pattern);
+                assert_eq!(o.targets, vec!["%.o"]);
+                assert_eq!(o.prerequsities, vec!["%.c", "$(HEADERS)"]);
+                assert_eq!(o.recipe.len(), 1);
+            }
+            Err(nom::Err::Error(e)) | Err(nom::Err::Failure(e)) => {
+                println!("{}", convert_error(data, e));
+                panic!();
+            }
+            Err(nom::Err::Incomplete(_)) => unreachable!(),
+        }
+        assert_eq!(
+            parse_rule('\t')("main.o: main.c\n").map(|(_, o)| o.kind),
+            Ok(RuleKind::Explicit)
+        );
+        // a % inside a reference doesn't make a pattern
+        assert_eq!(
+            parse_rule('\t')("$(SRCS:%.c=%.d): x\n").map(|(_, o)| o.kind),
+            Ok(RuleKind::Explicit)
+        );
+    }
+
+    #[test]
+    fn test_parse_static_pattern_rule() {
+        let data = "$(OBJS) extra.o: %.o: %.c defs.h\n\t$(CC) -c $<\n";
+        let res = parse_rule('\t')(data);
+        match res {
+            Ok((_, o)) => {
+                assert_eq!(o.kind, RuleKind::StaticPattern("%.o"));
+                assert_eq!(o.targets, vec!["$(OBJS)", "extra.o"]);
+                assert_eq!(o.prerequsities, vec!["%.c", "defs.h"]);
+                assert_eq!(o.recipe.len(), 1);
+            }
+            Err(nom::Err::Error(e)) | Err(nom::Err::Failure(e)) => {
+                println!("{}", convert_error(data, e));
+                panic!();
+            }
+            Err(nom::Err::Incomplete(_)) => unreachable!(),
+        }
+        assert_eq!(
+            parse_rule('\t')("foo.o: %.o:\n").map(|(_, o)| (o.kind, o.prerequsities)),
+            Ok((RuleKind::StaticPattern("%.o"), vec![]))
+        );
+        match parse_rule('\t')("foo.o: foo.c: %.c\n") {
+            Err(nom::Err::Failure(_)) => {}
+            r => panic!("expected target pattern error, got {:?}", r),
+        }
+    }
+
+    #[test]
+    fn test_parse_order_only_prereqs() {
+        let data = "obj/foo.o: foo.c foo.h | obj \\\n  bin\n\tcc -c foo.c -o $@\n";
+        let res = parse_rule('\t')(data);
+        match res {
+            Ok((_, o)) => {
+                assert_eq!(o.prerequsities, vec!["foo.c", "foo.h"]);
+                assert_eq!(o.order_only, vec!["obj", "bin"]);
+                assert_eq!(o.recipe.len(), 1);
+            }
+            Err(nom::Err::Error(e)) | Err(nom::Err::Failure(e)) => {
+                println!("{}", convert_error(data, e));
+                panic!();
+            }
+            Err(nom::Err::Incomplete(_)) => unreachable!(),
+        }
+        assert_eq!(
+            parse_rule('\t')("all: |out\n")
+                .map(|(_, o)| (o.prerequsities.len(), names(&o.order_only))),
+            Ok((0, vec!["out"]))
+        );
+        assert_eq!(
+            parse_rule('\t')("foo.o: %.o: %.c | obj\n")
+                .map(|(_, o)| (names(&o.prerequsities), names(&o.order_only))),
+            Ok((vec!["%.c"], vec!["obj"]))
+        );
+    }
+
+    #[test]
+    fn test_parse_double_colon_rules() {
+        let data = "clean::\n\trm a\nclean:: b\n\trm b\nall: clean\n";
+        let res = parse_makefile(data);
+        match res {
+            Ok((_, o)) => {
+                let rules = o.rules();
+                assert_eq!(rules.len(), 3);
+                assert!(rules[0].double_colon);
+                assert_eq!(rules[0].targets, vec!["clean"]);
+                assert_eq!(rules[0].recipe[0].command, "rm a");
+                assert!(rules[1].double_colon);
+                assert_eq!(rules[1].prerequsities, vec!["b"]);
+                assert!(!rules[2].double_colon);
+            }
+            Err(nom::Err::Error(e)) | Err(nom::Err::Failure(e)) => {
+                println!("{}", convert_error(data, e));
+                panic!();
+            }
+            Err(nom::Err::Incomplete(_)) => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn test_parse_target_variables() {
+        let data = "debug: CFLAGS += -g\n%.o: private OPT := -O0\nprog: main.o\n\tcc main.o\n";
+        let res = parse_makefile(data);
+        match res {
+            Ok((_, o)) => {
+                let vars = o.target_variables();
+                assert_eq!(vars.len(), 2);
+                assert_eq!(vars[0].targets, vec!["debug"]);
+                assert_eq!(vars[0].variable.name, "CFLAGS");
+                assert_eq!(vars[0].variable.assignment, Assignment::Append);
+                assert_eq!(vars[0].variable.value, vec!["-g"]);
+                assert_eq!(vars[1].targets, vec!["%.o"]);
+                assert_eq!(vars[1].variable.name, "OPT");
+                assert_eq!(vars[1].variable.assignment, Assignment::Simple);
+                assert!(vars[1].variable.modifiers.private);
+                assert_eq!(o.rules().len(), 1);
+                assert!(o.variables().is_empty());
+            }
+            Err(nom::Err::Error(e)) | Err(nom::Err::Failure(e)) => {
+                println!("{}", convert_error(data, e));
+                panic!();
+            }
+            Err(nom::Err::Incomplete(_)) => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn test_parse_inline_recipe() {
+        let data = "clean: ; rm -f *.o\nall: prog;@echo done\n\techo again\n";
+        let res = parse_makefile(data);
+        match res {
+            Ok((_, o)) => {
+                let rules = o.rules();
+                assert_eq!(rules.len(), 2);
+                assert!(rules[0].prerequsities.is_empty());
+                assert_eq!(rules[0].recipe[0].command, "rm -f *.o");
+                assert_eq!(rules[1].prerequsities, vec!["prog"]);
+                let recipe = &rules[1].recipe;
+                assert_eq!(recipe.len(), 2);
+                assert_eq!((recipe[0].command, recipe[0].silent), ("echo done", true));
+                assert_eq!((recipe[1].command, recipe[1].silent), ("echo again", false));
             }
             Err(nom::Err::Error(e)) | Err(nom::Err::Failure(e)) => {
                 println!("{}", convert_error(data, e));
@@ -440,18 +1909,27 @@ mod test {
             }
             Err(nom::Err::Incomplete(_)) => unreachable!(),
         }
+        assert_eq!(
+            parse_rule('\t')("%.o: %.c | obj ;")
+                .map(|(_, o)| (names(&o.order_only), o.recipe.len())),
+            Ok((vec!["obj"], 1))
+        );
     }
 
     #[test]
-    fn test_parse_target_no_prereq() {
-        let data =
-        "clean :\n\trm edit main.o kbd.o command.o display.o \\\n\t\tinsert.o search.o files.o utils.o\n";
-        let res = parse_rule(data);
+    fn test_parse_grouped_targets() {
+        let data = "foo.h foo.c &: foo.y\n\tbison --defines=foo.h -o foo.c foo.y\na&b.o b&:c\n";
+        let res = parse_makefile(data);
         match res {
             Ok((_, o)) => {
-                assert!(o.targets.contains(&"clean"));
-                assert_eq!(o.prerequsities.len(), 0);
-                assert_eq!(o.recipe.len(), 2);
+                let rules = o.rules();
+                assert_eq!(rules[0].targets, vec!["foo.h", "foo.c"]);
+                assert_eq!(rules[0].prerequsities, vec!["foo.y"]);
+                assert!(rules[0].grouped);
+                assert!(!rules[0].double_colon);
+                assert_eq!(rules[1].targets, vec!["a&b.o", "b"]);
+                assert_eq!(rules[1].prerequsities, vec!["c"]);
+                assert!(rules[1].grouped);
             }
             Err(nom::Err::Error(e)) | Err(nom::Err::Failure(e)) => {
                 println!("{}", convert_error(data, e));
@@ -459,19 +1937,30 @@ mod test {
             }
             Err(nom::Err::Incomplete(_)) => unreachable!(),
         }
+        assert_eq!(
+            parse_rule('\t')("a b &:: c\n").map(|(_, o)| (o.grouped, o.double_colon)),
+            Ok((true, true))
+        );
+        assert!(!parse_rule('\t')("a b: c\n").unwrap().1.grouped);
     }
 
     #[test]
-    fn test_parse_target_split_prereqs_and_recipe() {
-        let data = "edit : main.o kbd.o command.o display.o \\\n\tinsert.o search.o files.o utils.o\n\tcc -o edit main.o kbd.o command.o display.o \\\n\tinsert.o search.o files.o utils.o\n";
-        let res = parse_rule(data);
+    fn test_parse_comments() {
+        let data = "  # indented comment\n \t\nA = a \\# b # comment\nB = b # comment \\\n  still comment\nC := \\\\# c\nall: foo \\#bar # comment\n\techo # kept\n  # between\n\n   \n\techo two\n\t# shell comment\nx: ; echo # kept too\n";
+        let res = parse_makefile(data);
         match res {
             Ok((_, o)) => {
-                assert!(o.targets.contains(&"edit"));
-                assert!(o.prerequsities.contains(&"main.o"));
-                assert!(o.prerequsities.contains(&"utils.o"));
-                assert_eq!(o.prerequsities.len(), 8);
-                assert_eq!(o.recipe.len(), 2);
+                let vars = o.variables();
+                assert_eq!(vars.len(), 3);
+                assert_eq!(vars[0].value, vec!["a \\# b "]);
+                assert_eq!(vars[1].value, vec!["b "]);
+                assert_eq!(vars[2].value, vec!["\\\\"]);
+                let rules = o.rules();
+                assert_eq!(rules.len(), 2);
+                assert_eq!(rules[0].prerequsities, vec!["foo", "\\#bar"]);
+                let recipe: Vec<&str> = rules[0].recipe.iter().map(|l| l.command).collect();
+                assert_eq!(recipe, vec!["echo # kept", "echo two", "# shell comment"]);
+                assert_eq!(rules[1].recipe[0].command, "echo # kept too");
             }
             Err(nom::Err::Error(e)) | Err(nom::Err::Failure(e)) => {
                 println!("{}", convert_error(data, e));
@@ -479,12 +1968,61 @@ mod test {
             }
             Err(nom::Err::Incomplete(_)) => unreachable!(),
         }
+        assert!(parse_makefile("all:\n\techo\n  ").is_ok());
+    }
+
+    #[test]
+    fn test_parse_spans() {
+        let data = "CC = gcc\n\nifdef CC\nendif\n  foo: a.c \\\n\tb.c | obj\n\t@$(CC) -c $^\nfoo: CFLAGS += -g\n";
+        let (_, m) = parse_file("Makefile", data).unwrap();
+        let span = |line, column| Span {
+            file: "Makefile",
+            line,
+            column,
+        };
+        assert_eq!(m.statements[0].span(), span(1, 1));
+        assert_eq!(m.statements[1].span(), span(3, 1));
+        let rules = m.rules();
+        assert_eq!(rules[0].span, span(5, 3));
+        let prerequisites: Vec<Span> = rules[0]
+            .prerequsities
+            .iter()
+            .chain(rules[0].order_only.iter())
+            .map(|p| p.span)
+            .collect();
+        assert_eq!(prerequisites, vec![span(5, 8), span(6, 2), span(6, 8)]);
+        assert_eq!(rules[0].recipe[0].span, span(7, 3));
+        let variables = m.target_variables();
+        assert_eq!(variables[0].span, span(8, 1));
+        assert_eq!(variables[0].variable.span, span(8, 6));
+        assert_eq!(span(8, 6).to_string(), "Makefile:8:6");
+    }
+
+    #[test]
+    fn test_parse_recovering() {
+        let data = "a = 1\nnot a rule\n\techo skipped\nb: c\n    echo spaces\nifdef a\nfoo.o: foo: %.c\nendif\nendif\nc: d\n";
+        let (m, diagnostics) = parse_recovering("Makefile", data);
+        let messages: Vec<String> = diagnostics.iter().map(|d| d.to_string()).collect();
+        assert_eq!(
+            messages,
+            vec![
+                "Makefile:2:1: missing separator",
+                "Makefile:5:5: recipe line starts with spaces, did you mean a tab?",
+                "Makefile:7:1: target pattern contains no '%'",
+                "Makefile:9:1: else or endif without a matching conditional",
+            ]
+        );
+        // everything else is still parsed
+        assert_eq!(m.variables().len(), 1);
+        let targets: Vec<&str> = m.rules().iter().map(|r| r.targets[0]).collect();
+        assert_eq!(targets, vec!["b", "c"]);
+        assert_eq!(m.statements.len(), 4);
     }
 
     #[test]
     fn test_parse_phony_target() {
         let data = ".PHONY: all\n";
-        let res = parse_rule(data);
+        let res = parse_rule('\t')(data);
         match res {
             Ok((_, o)) => {
                 assert!(o.targets.contains(&".PHONY"));
@@ -500,6 +2038,111 @@ mod test {
         }
     }
 
+    #[test]
+    fn test_parse_rule_references() {
+        let data = "$(OBJS): $(SRCS:.c=.o) $(call f, a b) | $(dir $@)\n";
+        let (_, o) = parse_rule('\t')(data).unwrap();
+        assert_eq!(o.targets, vec!["$(OBJS)"]);
+        assert_eq!(
+            names(&o.prerequsities),
+            vec!["$(SRCS:.c=.o)", "$(call f, a b)"]
+        );
+        assert_eq!(names(&o.order_only), vec!["$(dir $@)"]);
+    }
+
+    #[test]
+    fn test_parse_expression() {
+        use Expr::*;
+        let reference = |name| Reference(vec![Literal(name)]);
+        assert_eq!(parse_expression(""), Ok(("", vec![])));
+        assert_eq!(
+            parse_expression("$(CC) -o $@ ${LIBS} $$HOME $"),
+            Ok((
+                "",
+                vec![
+                    reference("CC"),
+                    Literal(" -o "),
+                    reference("@"),
+                    Literal(" "),
+                    reference("LIBS"),
+                    Literal(" "),
+                    Literal("$"),
+                    Literal("HOME "),
+                ]
+            ))
+        );
+        assert_eq!(
+            parse_expression("$($(ARCH)_FLAGS)"),
+            Ok((
+                "",
+                vec![Reference(vec![reference("ARCH"), Literal("_FLAGS")])]
+            ))
+        );
+        assert_eq!(
+            parse_expression("$(SRCS:%.c=%.o)"),
+            Ok((
+                "",
+                vec![Substitution {
+                    name: vec![Literal("SRCS")],
+                    from: vec![Literal("%.c")],
+                    to: vec![Literal("%.o")],
+                }]
+            ))
+        );
+        assert_eq!(
+            parse_expression("$(patsubst %.c,%.o,$(filter %.c,$(SRCS)))"),
+            Ok((
+                "",
+                vec![Call {
+                    function: "patsubst",
+                    args: vec![
+                        vec![Literal("%.c")],
+                        vec![Literal("%.o")],
+                        vec![Call {
+                            function: "filter",
+                            args: vec![vec![Literal("%.c")], vec![reference("SRCS")]],
+                        }],
+                    ],
+                }]
+            ))
+        );
+        // commas after the last argument, or inside brackets, are literal
+        assert_eq!(
+            parse_expression("${info a, (b,c) {d,e}}"),
+            Ok((
+                "",
+                vec![Call {
+                    function: "info",
+                    args: vec![vec![Literal("a, (b,c) {d,e}")]],
+                }]
+            ))
+        );
+        assert_eq!(
+            parse_expression("$(subst (,),a(b,c)d)"),
+            Ok((
+                "",
+                vec![Call {
+                    function: "subst",
+                    args: vec![vec![Literal("(,)")], vec![Literal("a(b,c)d")]],
+                }]
+            ))
+        );
+        // a function name must be followed by whitespace
+        assert_eq!(
+            parse_expression("$(sort)"),
+            Ok(("", vec![reference("sort")]))
+        );
+        assert_eq!(
+            parse_expression("$(a:b)"),
+            Ok((
+                "",
+                vec![Reference(vec![Literal("a"), Literal(":"), Literal("b")])]
+            ))
+        );
+        assert!(parse_expression("$(CC").is_err());
+        assert!(parse_expression("$(if a,b").is_err());
+    }
+
     #[test]
     fn test_parse_target_names() {
         assert_eq!(parse_target_names("main.c"), Ok(("", "main.c")));
@@ -517,6 +2160,376 @@ mod test {
         );
     }
 
+    #[test]
+    fn test_parse_condition() {
+        assert_eq!(
+            parse_condition("ifeq ($(CC),gcc)\n"),
+            Ok(("", Condition::IfEq("$(CC)", "gcc")))
+        );
+        assert_eq!(
+            parse_condition("ifneq ( $(call f,a,b) , )\n"),
+            Ok(("", Condition::IfNeq("$(call f,a,b)", "")))
+        );
+        assert_eq!(
+            parse_condition("ifeq \"a\" 'b' # comment\n"),
+            Ok(("", Condition::IfEq("a", "b")))
+        );
+        assert_eq!(
+            parse_condition("  ifdef DEBUG\n"),
+            Ok(("", Condition::IfDef("DEBUG")))
+        );
+        assert_eq!(
+            parse_condition("ifndef $(NAME)"),
+            Ok(("", Condition::IfNdef("$(NAME)")))
+        );
+        assert!(parse_condition("ifeq a b\n").is_err());
+        assert!(parse_condition("ifdefined = 1\n").is_err());
+    }
+
+    #[test]
+    fn test_parse_conditional_else_chain() {
+        let data = "ifeq ($(OS),windows)\nEXE = .exe\nelse ifdef UNIX\nEXE =\nelse\nall:\nendif\n";
+        let res = parse_conditional(data, &mut State::new("", data));
+        match res {
+            Ok((i, o)) => {
+                assert_eq!(i, "");
+                assert_eq!(o.condition, Condition::IfEq("$(OS)", "windows"));
+                assert_eq!(o.then.len(), 1);
+                assert_eq!(o.otherwise.len(), 1);
+                match &o.otherwise[0] {
+                    Statement::Conditional(c) => {
+                        assert_eq!(c.condition, Condition::IfDef("UNIX"));
+                        assert_eq!(c.then.len(), 1);
+                        assert_eq!(c.otherwise.len(), 1);
+                    }
+                    s => panic!("unexpected statement {:?}", s),
+                }
+            }
+            Err(nom::Err::Error(e)) | Err(nom::Err::Failure(e)) => {
+                println!("{}", convert_error(data, e));
+                panic!();
+            }
+            Err(nom::Err::Incomplete(_)) => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn test_parse_conditional_in_rule() {
+        let data = "foo:\n\tcc -o foo\n  endif\n";
+        let res = parse_statements(data, &mut State::new("", data));
+        assert_eq!(res.as_ref().map(|(i, _)| *i), Ok("  endif\n"));
+    }
+
+    #[test]
+    fn test_parse_recipe_in_conditional() {
+        // the recipe carries on inside a conditional that isn't indented
+        let (_, m) =
+            parse_makefile("X = 1\nall:\nifdef X\n\techo a\nelse\n\techo b\nendif\n").unwrap();
+        let c = match &m.statements[2] {
+            Statement::Conditional(c) => c,
+            s => panic!("expected a conditional, got {:?}", s),
+        };
+        fn commands<'a>(s: &[Statement<'a>]) -> Vec<&'a str> {
+            match s {
+                [Statement::Recipe(r)] => r.lines.iter().map(|l| l.command).collect(),
+                s => panic!("expected a recipe, got {:?}", s),
+            }
+        }
+        assert_eq!(commands(&c.then), vec!["echo a"]);
+        assert_eq!(commands(&c.otherwise), vec!["echo b"]);
+        assert_eq!(c.then[0].span().to_string(), "4:2");
+
+        // after the prefix a conditional is part of the recipe
+        let (_, m) = parse_makefile("all:\n\tifeq ($(X),1)\n\techo x\n\tendif\n").unwrap();
+        let recipe: Vec<&str> = m.rules()[0].recipe.iter().map(|l| l.command).collect();
+        assert_eq!(recipe, vec!["ifeq ($(X),1)", "echo x", "endif"]);
+
+        // an assignment ends the recipe
+        assert!(parse_makefile("all:\nifdef X\nY = 1\n\techo a\nendif\n").is_err());
+    }
+
+    #[test]
+    fn test_parse_conditional_errors() {
+        assert!(parse_makefile("ifdef FOO\nfoo:\n").is_err());
+        assert!(parse_makefile("ifdef FOO\nelse\nfoo:\n").is_err());
+        assert!(parse_makefile("foo:\nendif\n").is_err());
+        assert!(parse_makefile("ifdef FOO\nelse junk\nendif\n").is_err());
+    }
+
+    #[test]
+    fn test_parse_makefile_conditionals() {
+        let data = fs::read_to_string("./assets/03-conditionals.mk").expect("ohnoes");
+        let data_s = &data[..];
+        let res = parse_makefile(data_s);
+        match res {
+            Ok((_, o)) => {
+                assert_eq!(o.statements.len(), 5);
+                assert_eq!(o.variables().len(), 2);
+                assert_eq!(o.rules().len(), 1);
+                match &o.statements[3] {
+                    Statement::Conditional(c) => {
+                        assert_eq!(c.condition, Condition::IfDef("DEBUG"));
+                        match &c.otherwise[0] {
+                            Statement::Conditional(c) => {
+                                assert_eq!(c.condition, Condition::IfNeq("$(OPTIMIZE)", ""));
+                                assert_eq!(c.otherwise.len(), 1);
+                            }
+                            s => panic!("unexpected statement {:?}", s),
+                        }
+                    }
+                    s => panic!("unexpected statement {:?}", s),
+                }
+            }
+            Err(nom::Err::Error(e)) | Err(nom::Err::Failure(e)) => {
+                println!("{}", convert_error(data_s, e));
+                panic!();
+            }
+            Err(nom::Err::Incomplete(_)) => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn test_parse_define() {
+        let data = "define two-lines\n\techo foo\n\techo $(bar)\nendef\n";
+        assert_eq!(
+            parse_define(data),
+            Ok((
+                "",
+                Variable {
+                    name: "two-lines",
+                    assignment: Assignment::Recursive,
+                    value: vec!["\techo foo\n\techo $(bar)"],
+                    define: true,
+                    modifiers: Modifiers::default(),
+                    span: Span::default(),
+                }
+            ))
+        );
+        assert_eq!(
+            parse_define("define EMPTY :=\nendef"),
+            Ok((
+                "",
+                Variable {
+                    name: "EMPTY",
+                    assignment: Assignment::Simple,
+                    value: vec![""],
+                    define: true,
+                    modifiers: Modifiers::default(),
+                    span: Span::default(),
+                }
+            ))
+        );
+        let res = parse_define("define LIST +=\r\na\r\n\r\nb\r\n  endef # done\r\n");
+        assert_eq!(
+            res.as_ref().map(|(_, v)| v.assignment),
+            Ok(Assignment::Append)
+        );
+        assert_eq!(res.map(|(_, v)| v.value), Ok(vec!["a\r\n\r\nb"]));
+    }
+
+    #[test]
+    fn test_parse_define_nested() {
+        let data = "define outer\ndefine inner\nfoo\nendef\nendef\nall:\n";
+        let res = parse_define(data);
+        assert_eq!(
+            res.map(|(i, v)| (i, v.value)),
+            Ok(("all:\n", vec!["define inner\nfoo\nendef"]))
+        );
+    }
+
+    #[test]
+    fn test_parse_define_errors() {
+        match parse_define("define FOO\nbar\n") {
+            Err(nom::Err::Failure(_)) => {}
+            r => panic!("expected missing endef, got {:?}", r),
+        }
+        match parse_define("define = foo\n") {
+            Err(nom::Err::Error(_)) => {}
+            r => panic!("expected a variable called define, got {:?}", r),
+        }
+    }
+
+    #[test]
+    fn test_parse_undefine() {
+        assert_eq!(
+            parse_undefine("undefine foo\n"),
+            Ok((
+                "",
+                Undefine {
+                    name: "foo",
+                    r#override: false,
+                    span: Span::default(),
+                }
+            ))
+        );
+        assert_eq!(
+            parse_undefine("override undefine foo\n"),
+            Ok((
+                "",
+                Undefine {
+                    name: "foo",
+                    r#override: true,
+                    span: Span::default(),
+                }
+            ))
+        );
+        assert!(parse_undefine("undefine\n").is_err());
+    }
+
+    #[test]
+    fn test_parse_modifiers() {
+        let res = parse_assignment("export CC = clang\n");
+        assert_eq!(
+            res.map(|(_, v)| (v.name, v.modifiers)),
+            Ok((
+                "CC",
+                Modifiers {
+                    export: true,
+                    r#override: false,
+                    private: false
+                }
+            ))
+        );
+        let res = parse_assignment("override  private CFLAGS += -Werror\n");
+        assert_eq!(
+            res.map(|(_, v)| (v.name, v.assignment, v.modifiers)),
+            Ok((
+                "CFLAGS",
+                Assignment::Append,
+                Modifiers {
+                    export: false,
+                    r#override: true,
+                    private: true
+                }
+            ))
+        );
+        let res = parse_assignment("override define BODY\nfoo\nendef\n");
+        assert_eq!(
+            res.map(|(_, v)| (v.define, v.modifiers.r#override)),
+            Ok((true, true))
+        );
+        let res = parse_assignment("export = value\n");
+        assert_eq!(
+            res.map(|(_, v)| (v.name, v.modifiers)),
+            Ok(("export", Modifiers::default()))
+        );
+        assert!(parse_assignment("export FOO\n").is_err());
+    }
+
+    #[test]
+    fn test_parse_export() {
+        assert_eq!(
+            parse_export("export\n"),
+            Ok((
+                "",
+                Export {
+                    names: vec![],
+                    export: true,
+                    span: Span::default(),
+                }
+            ))
+        );
+        assert_eq!(
+            parse_export("unexport FOO $(BAR)\n"),
+            Ok((
+                "",
+                Export {
+                    names: vec!["FOO", "$(BAR)"],
+                    export: false,
+                    span: Span::default(),
+                }
+            ))
+        );
+    }
+
+    #[test]
+    fn test_parse_word() {
+        assert_eq!(parse_word("foo.mk bar.mk"), Ok((" bar.mk", "foo.mk")));
+        assert_eq!(
+            parse_word("$(addsuffix .mk, a b)\n"),
+            Ok(("\n", "$(addsuffix .mk, a b)"))
+        );
+        assert_eq!(parse_word("foo#bar"), Ok(("#bar", "foo")));
+        assert!(parse_word(" foo").is_err());
+    }
+
+    #[test]
+    fn test_parse_vpath() {
+        assert_eq!(
+            parse_vpath("vpath %.h include:$(GEN) # headers\n"),
+            Ok((
+                "",
+                Vpath {
+                    pattern: Some("%.h"),
+                    directories: vec!["include:$(GEN)"],
+                    span: Span::default(),
+                }
+            ))
+        );
+        assert_eq!(
+            parse_vpath("vpath %.c\n"),
+            Ok((
+                "",
+                Vpath {
+                    pattern: Some("%.c"),
+                    directories: vec![],
+                    span: Span::default(),
+                }
+            ))
+        );
+        assert_eq!(
+            parse_vpath("vpath"),
+            Ok((
+                "",
+                Vpath {
+                    pattern: None,
+                    directories: vec![],
+                    span: Span::default(),
+                }
+            ))
+        );
+        let (_, m) = parse_makefile("vpath = x\nVPATH = src:include\n").unwrap();
+        assert_eq!(m.variables().len(), 2);
+    }
+
+    #[test]
+    fn test_parse_include() {
+        assert_eq!(
+            parse_include("include foo.mk $(DEPS) # comment\n"),
+            Ok((
+                "",
+                Include {
+                    names: vec!["foo.mk", "$(DEPS)"],
+                    optional: false,
+                    span: Span::default(),
+                }
+            ))
+        );
+        assert_eq!(
+            parse_include("-include $(SRCS:.c=.d)"),
+            Ok((
+                "",
+                Include {
+                    names: vec!["$(SRCS:.c=.d)"],
+                    optional: true,
+                    span: Span::default(),
+                }
+            ))
+        );
+        assert_eq!(
+            parse_include("sinclude\n"),
+            Ok((
+                "",
+                Include {
+                    names: vec![],
+                    optional: true,
+                    span: Span::default(),
+                }
+            ))
+        );
+        assert!(parse_include("included: foo\n").is_err());
+    }
+
     #[test]
     fn test_parse_makefile_simple() {
         let data = fs::read_to_string("./assets/01-simple.mk").expect("ohnoes");
@@ -524,14 +2537,20 @@ mod test {
         let res = parse_makefile(data_s);
         match res {
             Ok((_, o)) => {
-                assert_eq!(o.variables.len(), 3);
-                assert_eq!(o.rules.len(), 10);
-                assert!(o.rules[0].targets.contains(&"edit"));
-                assert_eq!(o.rules[0].prerequsities.len(), 8);
-                assert!(o.rules[0].prerequsities.contains(&"main.o"));
-                assert!(o.rules[0].prerequsities.contains(&"utils.o"));
-                assert!(o.rules[1].targets.contains(&"main.o"));
-                assert!(o.rules[9].targets.contains(&"clean"));
+                assert_eq!(o.variables().len(), 3);
+                assert_eq!(o.rules().len(), 10);
+                assert!(o.rules()[0].targets.contains(&"edit"));
+                assert_eq!(o.rules()[0].prerequsities.len(), 8);
+                assert!(o.rules()[0]
+                    .prerequsities
+                    .iter()
+                    .any(|p| p.name == "main.o"));
+                assert!(o.rules()[0]
+                    .prerequsities
+                    .iter()
+                    .any(|p| p.name == "utils.o"));
+                assert!(o.rules()[1].targets.contains(&"main.o"));
+                assert!(o.rules()[9].targets.contains(&"clean"));
             }
             Err(nom::Err::Error(e)) | Err(nom::Err::Failure(e)) => {
                 println!("{}", convert_error(data_s, e));
@@ -548,12 +2567,12 @@ mod test {
         let res = parse_makefile(data_s);
         match res {
             Ok((_, o)) => {
-                assert_eq!(o.variables.len(), 23);
-                assert_eq!(o.rules.len(), 23);
-                assert!(o.rules[0].targets.contains(&".PHONY"));
-                assert_eq!(o.rules[0].prerequsities.len(), 1);
-                assert!(o.rules[0].prerequsities.contains(&"all"));
-                assert!(o.rules[22].targets.contains(&"tar.zoo"));
+                assert_eq!(o.variables().len(), 23);
+                assert_eq!(o.rules().len(), 23);
+                assert!(o.rules()[0].targets.contains(&".PHONY"));
+                assert_eq!(o.rules()[0].prerequsities.len(), 1);
+                assert!(o.rules()[0].prerequsities.iter().any(|p| p.name == "all"));
+                assert!(o.rules()[22].targets.contains(&"tar.zoo"));
             }
             Err(nom::Err::Error(e)) | Err(nom::Err::Failure(e)) => {
                 println!("{}", convert_error(data_s, e));
@@ -562,5 +2581,4 @@ mod test {
             Err(nom::Err::Incomplete(_)) => unreachable!(),
         }
     }
-
 }
@@ -0,0 +1,109 @@
+// A Match is the result of matching a file name against the target pattern
+// of a pattern rule
+#[derive(Debug, PartialEq, Clone)]
+pub struct Match {
+    // the directory removed from the file name before it was matched,
+    // including the trailing slash
+    pub dir: String,
+    // the text matched by %
+    pub stem: String,
+}
+
+impl Match {
+    // full_stem is the stem including the directory, as used for $*
+    pub fn full_stem(&self) -> String {
+        format!("{}{}", self.dir, self.stem)
+    }
+
+    // substitute fills in a prerequisite pattern with the stem and adds back
+    // the directory. Prerequisites without a % are used as they are.
+    pub fn substitute(&self, pattern: &str) -> String {
+        if pattern.contains('%') {
+            format!("{}{}", self.dir, substitute(pattern, &self.stem))
+        } else {
+            pattern.to_string()
+        }
+    }
+}
+
+// stem returns the text matched by the % in pattern, if name matches it.
+// A pattern without a % only matches itself, with an empty stem.
+pub fn stem<'a>(pattern: &str, name: &'a str) -> Option<&'a str> {
+    let idx = match pattern.find('%') {
+        Some(idx) => idx,
+        None if pattern == name => return Some(""),
+        None => return None,
+    };
+    let (prefix, suffix) = (&pattern[..idx], &pattern[idx + 1..]);
+    if name.len() >= prefix.len() + suffix.len()
+        && name.starts_with(prefix)
+        && name.ends_with(suffix)
+    {
+        Some(&name[prefix.len()..name.len() - suffix.len()])
+    } else {
+        None
+    }
+}
+
+// substitute replaces the first % in pattern with stem
+pub fn substitute(pattern: &str, stem: &str) -> String {
+    pattern.replacen('%', stem, 1)
+}
+
+// match_target matches a file name against the target pattern of a pattern
+// rule, where % must match at least one character. When the pattern has no
+// slash the directory part of the name is removed before matching, so that
+// `%.o` matches `src/foo.o` with the stem `foo` in the directory `src/`.
+pub fn match_target(pattern: &str, name: &str) -> Option<Match> {
+    let (dir, file) = match name.rfind('/') {
+        Some(idx) if !pattern.contains('/') => name.split_at(idx + 1),
+        _ => ("", name),
+    };
+    match stem(pattern, file) {
+        Some(stem) if !stem.is_empty() && pattern.contains('%') => Some(Match {
+            dir: dir.to_string(),
+            stem: stem.to_string(),
+        }),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_stem() {
+        assert_eq!(stem("%.c", "foo.c"), Some("foo"));
+        assert_eq!(stem("lib%.a", "libz.a"), Some("z"));
+        assert_eq!(stem("%.c", ".c"), Some(""));
+        assert_eq!(stem("%.c", "foo.h"), None);
+        assert_eq!(stem("a%a", "a"), None);
+        assert_eq!(stem("foo.c", "foo.c"), Some(""));
+    }
+
+    #[test]
+    fn test_match_target() {
+        let m = match_target("%.o", "foo.o").unwrap();
+        assert_eq!(m.full_stem(), "foo");
+        assert_eq!(m.substitute("%.c"), "foo.c");
+        assert_eq!(m.substitute("defs.h"), "defs.h");
+        assert_eq!(match_target("%.o", ".o"), None);
+        assert_eq!(match_target("%.o", "foo.c"), None);
+        assert_eq!(match_target("foo.o", "foo.o"), None);
+    }
+
+    #[test]
+    fn test_match_target_directories() {
+        let m = match_target("e%t", "src/eat").unwrap();
+        assert_eq!(m.full_stem(), "src/a");
+        assert_eq!(m.substitute("c%r"), "src/car");
+        assert_eq!(m.substitute("common.h"), "common.h");
+
+        let m = match_target("src/%.o", "src/lib/foo.o").unwrap();
+        assert_eq!(m.dir, "");
+        assert_eq!(m.full_stem(), "lib/foo");
+        assert_eq!(m.substitute("src/%.c"), "src/lib/foo.c");
+        assert_eq!(match_target("src/%.o", "foo.o"), None);
+    }
+}
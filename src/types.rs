@@ -1,3 +1,37 @@
+use std::fmt;
+
+// A Span is where something was written: the file it was read from and the
+// line and column it starts at, both counting from 1
+#[derive(Debug, PartialEq, Clone, Copy, Default)]
+pub struct Span<'a> {
+    pub file: &'a str,
+    pub line: usize,
+    pub column: usize,
+}
+
+impl fmt::Display for Span<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.file.is_empty() {
+            write!(f, "{}:{}", self.line, self.column)
+        } else {
+            write!(f, "{}:{}:{}", self.file, self.line, self.column)
+        }
+    }
+}
+
+// A Diagnostic is a problem found while parsing, and where it was found
+#[derive(Debug, PartialEq, Clone)]
+pub struct Diagnostic<'a> {
+    pub span: Span<'a>,
+    pub message: String,
+}
+
+impl fmt::Display for Diagnostic<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: {}", self.span, self.message)
+    }
+}
+
 // Assignment represents the types of assignment
 #[derive(Debug, PartialEq, Clone, Copy)]
 pub enum Assignment {
@@ -8,25 +42,260 @@ pub enum Assignment {
     Shell,
 }
 
-// A Variable has a name, assignment type and a value
+// Modifiers are the keywords that may be written before a variable definition.
+// export passes the variable to the environment of recipes, override lets it
+// replace a value given on the command line and private stops targets from
+// inheriting it.
+#[derive(Debug, PartialEq, Clone, Copy, Default)]
+pub struct Modifiers {
+    pub export: bool,
+    pub r#override: bool,
+    pub private: bool,
+}
+
+// A Variable has a name, assignment type and a value.
+// Variables read from a define block have their body as a single value,
+// with the newlines between lines kept intact.
 #[derive(Debug, PartialEq, Clone)]
 pub struct Variable<'a> {
     pub name: &'a str,
     pub assignment: Assignment,
     pub value: Vec<&'a str>,
+    pub define: bool,
+    pub modifiers: Modifiers,
+    pub span: Span<'a>,
+}
+
+// RuleKind distinguishes explicit rules from pattern rules, whose targets
+// contain a % that matches any non-empty stem, and static pattern rules,
+// where each target is matched against the target pattern to find the stem
+// used in its prerequisites
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum RuleKind<'a> {
+    Explicit,
+    Pattern,
+    StaticPattern(&'a str),
+}
+
+// A RecipeLine is a command from a recipe along with the prefixes written
+// before it: @ stops the command being echoed, - ignores it failing and +
+// runs it even when commands are only being printed
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct RecipeLine<'a> {
+    pub command: &'a str,
+    pub silent: bool,
+    pub ignore_errors: bool,
+    pub always: bool,
+    pub span: Span<'a>,
+}
+
+// A Prerequisite is the name of a prerequisite of a rule and where it was written
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct Prerequisite<'a> {
+    pub name: &'a str,
+    pub span: Span<'a>,
+}
+
+impl AsRef<str> for Prerequisite<'_> {
+    fn as_ref(&self) -> &str {
+        self.name
+    }
 }
 
-// A Rule contains a list of targets, prerequisites and the recipe to build them
+impl PartialEq<&str> for Prerequisite<'_> {
+    fn eq(&self, other: &&str) -> bool {
+        self.name == *other
+    }
+}
+
+// A Rule contains a list of targets, prerequisites and the recipe to build them.
+// Order-only prerequisites, listed after a |, are made before the targets
+// but don't cause them to be remade when they change.
+// Double-colon rules, written with ::, each have their own recipe even when
+// they share a target. Grouped targets, written with &:, are all made by a
+// single run of the recipe.
 #[derive(Debug, PartialEq, Clone)]
 pub struct Rule<'a> {
+    pub kind: RuleKind<'a>,
+    pub double_colon: bool,
+    pub grouped: bool,
+    pub targets: Vec<&'a str>,
+    pub prerequsities: Vec<Prerequisite<'a>>,
+    pub order_only: Vec<Prerequisite<'a>>,
+    pub recipe: Vec<RecipeLine<'a>>,
+    pub span: Span<'a>,
+}
+
+// A TargetVariable is a variable definition written after the colon of a
+// rule, such as `debug: CFLAGS += -g`. It applies while making its targets,
+// which may be patterns, and is inherited by their prerequisites unless it
+// is private.
+#[derive(Debug, PartialEq, Clone)]
+pub struct TargetVariable<'a> {
     pub targets: Vec<&'a str>,
-    pub prerequsities: Vec<&'a str>,
-    pub recipe: Vec<&'a str>,
+    pub variable: Variable<'a>,
+    pub span: Span<'a>,
+}
+
+// Condition is the test made by one of the conditional directives
+#[derive(Debug, PartialEq, Clone)]
+pub enum Condition<'a> {
+    IfEq(&'a str, &'a str),
+    IfNeq(&'a str, &'a str),
+    IfDef(&'a str),
+    IfNdef(&'a str),
+}
+
+// A Conditional holds the statements to read when its condition is true and
+// those to read when it is false. An `else ifeq ...` chain is represented as
+// a single nested Conditional in the otherwise branch.
+#[derive(Debug, PartialEq, Clone)]
+pub struct Conditional<'a> {
+    pub condition: Condition<'a>,
+    pub then: Vec<Statement<'a>>,
+    pub otherwise: Vec<Statement<'a>>,
+    pub span: Span<'a>,
+}
+
+// An Include names other Makefiles to read in place of the directive.
+// Optional includes (-include and sinclude) ignore files that don't exist.
+#[derive(Debug, PartialEq, Clone)]
+pub struct Include<'a> {
+    pub names: Vec<&'a str>,
+    pub optional: bool,
+    pub span: Span<'a>,
+}
+
+// A Vpath is a vpath directive. With a pattern and directories it adds
+// directories to search for files matching the pattern, with only a pattern
+// it clears the directories for that pattern and on its own it clears them all.
+#[derive(Debug, PartialEq, Clone)]
+pub struct Vpath<'a> {
+    pub pattern: Option<&'a str>,
+    pub directories: Vec<&'a str>,
+    pub span: Span<'a>,
+}
+
+// An Export marks variables to be passed to the environment of recipes or,
+// for unexport, to be kept out of it. An Export without names applies to
+// every variable.
+#[derive(Debug, PartialEq, Clone)]
+pub struct Export<'a> {
+    pub names: Vec<&'a str>,
+    pub export: bool,
+    pub span: Span<'a>,
+}
+
+// An Undefine removes a variable. It needs override to remove a variable
+// given on the command line.
+#[derive(Debug, PartialEq, Clone)]
+pub struct Undefine<'a> {
+    pub name: &'a str,
+    pub r#override: bool,
+    pub span: Span<'a>,
+}
+
+// A Recipe holds recipe lines that follow a conditional directive inside the
+// recipe of a rule. They belong to the rule before the conditional, which is
+// only known once the conditional has been evaluated.
+#[derive(Debug, PartialEq, Clone)]
+pub struct Recipe<'a> {
+    pub lines: Vec<RecipeLine<'a>>,
+    pub span: Span<'a>,
+}
+
+// An Expr is a piece of text that may be expanded. Text is a list of them:
+// `$(CC) -c $<` is a reference to CC, the literal ` -c ` and a reference
+// to <. Names and arguments are themselves text, since they may contain
+// further references.
+#[derive(Debug, PartialEq, Clone)]
+pub enum Expr<'a> {
+    // text without references. `$$` is the literal `$`
+    Literal(&'a str),
+    // $(name), ${name} or a single character name such as $@
+    Reference(Vec<Expr<'a>>),
+    // $(name:from=to)
+    Substitution {
+        name: Vec<Expr<'a>>,
+        from: Vec<Expr<'a>>,
+        to: Vec<Expr<'a>>,
+    },
+    // $(function arg,arg...)
+    Call {
+        function: &'a str,
+        args: Vec<Vec<Expr<'a>>>,
+    },
+}
+
+// A Statement is one of the top level constructs that make up a Makefile
+#[derive(Debug, PartialEq, Clone)]
+pub enum Statement<'a> {
+    Variable(Variable<'a>),
+    Rule(Rule<'a>),
+    TargetVariable(TargetVariable<'a>),
+    Conditional(Conditional<'a>),
+    Include(Include<'a>),
+    Undefine(Undefine<'a>),
+    Export(Export<'a>),
+    Vpath(Vpath<'a>),
+    Recipe(Recipe<'a>),
+}
+
+impl<'a> Statement<'a> {
+    // span returns where the statement was written
+    pub fn span(&self) -> Span<'a> {
+        match self {
+            Statement::Variable(v) => v.span,
+            Statement::Rule(r) => r.span,
+            Statement::TargetVariable(v) => v.span,
+            Statement::Conditional(c) => c.span,
+            Statement::Include(i) => i.span,
+            Statement::Undefine(u) => u.span,
+            Statement::Export(e) => e.span,
+            Statement::Vpath(v) => v.span,
+            Statement::Recipe(r) => r.span,
+        }
+    }
 }
 
-// Makefile represents the contents of the file
+// Makefile represents the contents of the file, in the order it was read
 #[derive(Debug, PartialEq, Clone)]
 pub struct Makefile<'a> {
-    pub variables: Vec<Variable<'a>>,
-    pub rules: Vec<Rule<'a>>,
+    pub statements: Vec<Statement<'a>>,
+}
+
+impl<'a> Makefile<'a> {
+    // variables returns the variables defined outside of any conditional
+    pub fn variables(&self) -> Vec<&Variable<'a>> {
+        self.statements
+            .iter()
+            .filter_map(|s| match s {
+                Statement::Variable(v) => Some(v),
+                _ => None,
+            })
+            .collect()
+    }
+
+    // rules returns the rules defined outside of any conditional
+    pub fn rules(&self) -> Vec<&Rule<'a>> {
+        self.statements
+            .iter()
+            .filter_map(|s| match s {
+                Statement::Rule(r) => Some(r),
+                _ => None,
+            })
+            .collect()
+    }
+
+    // target_variables returns the target-specific and pattern-specific
+    // variables defined outside of any conditional
+    pub fn target_variables(&self) -> Vec<&TargetVariable<'a>> {
+        self.statements
+            .iter()
+            .filter_map(|s| match s {
+                Statement::TargetVariable(v) => Some(v),
+                _ => None,
+            })
+            .collect()
+    }
 }
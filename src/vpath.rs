@@ -0,0 +1,91 @@
+// Search holds the directories to look in for files that aren't found where
+// they are named. Directories given by the vpath directive are searched first,
+// for the patterns the file matches in the order they were given, followed by
+// those in the VPATH variable.
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct Search {
+    patterns: Vec<(String, Vec<String>)>,
+    general: Vec<String>,
+}
+
+// split_dirs splits a list of directories separated by colons or whitespace
+pub fn split_dirs(text: &str) -> Vec<String> {
+    text.split(|c: char| c == ':' || c.is_whitespace())
+        .filter(|d| !d.is_empty())
+        .map(String::from)
+        .collect()
+}
+
+// matches checks a file name against a vpath pattern. A pattern without a %
+// only matches the name itself.
+fn matches(pattern: &str, name: &str) -> bool {
+    crate::pattern::stem(pattern, name).is_some()
+}
+
+impl Search {
+    // add adds directories to search for files matching pattern
+    pub fn add(&mut self, pattern: &str, dirs: Vec<String>) {
+        if !dirs.is_empty() {
+            self.patterns.push((pattern.to_string(), dirs));
+        }
+    }
+
+    // clear removes the directories for pattern, or for every pattern
+    pub fn clear(&mut self, pattern: Option<&str>) {
+        match pattern {
+            Some(pattern) => self.patterns.retain(|(p, _)| p != pattern),
+            None => self.patterns.clear(),
+        }
+    }
+
+    // general sets the directories searched for every file, from VPATH
+    pub fn general(&mut self, dirs: Vec<String>) {
+        self.general = dirs;
+    }
+
+    // find returns the path of the first file called name in the search
+    // directories, using exists to check for it. Absolute names aren't searched.
+    pub fn find<F: Fn(&str) -> bool>(&self, name: &str, exists: F) -> Option<String> {
+        if name.starts_with('/') {
+            return None;
+        }
+        self.patterns
+            .iter()
+            .filter(|(p, _)| matches(p, name))
+            .flat_map(|(_, dirs)| dirs)
+            .chain(&self.general)
+            .map(|d| format!("{}/{}", d.trim_end_matches('/'), name))
+            .find(|p| exists(p))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_find() {
+        let mut s = Search::default();
+        s.add("%.h", split_dirs("include:gen"));
+        s.add("%.c", split_dirs("lib"));
+        s.general(split_dirs("src: lib/"));
+        let files = [
+            "gen/defs.h",
+            "src/defs.h",
+            "src/main.c",
+            "lib/main.c",
+            "lib/util.o",
+        ];
+        let exists = |p: &str| files.contains(&p);
+        assert_eq!(s.find("defs.h", exists), Some("gen/defs.h".to_string()));
+        assert_eq!(s.find("main.c", exists), Some("lib/main.c".to_string()));
+        assert_eq!(s.find("util.o", exists), Some("lib/util.o".to_string()));
+        assert_eq!(s.find("/src/main.c", exists), None);
+        assert_eq!(s.find("missing.c", exists), None);
+
+        s.clear(Some("%.c"));
+        assert_eq!(s.find("main.c", exists), Some("src/main.c".to_string()));
+        s.clear(None);
+        assert_eq!(s.find("defs.h", exists), Some("src/defs.h".to_string()));
+    }
+}